@@ -0,0 +1,482 @@
+//! Parser for host expressions such as `web-[01:12].{prod,dev}`
+//!
+//! An expression is a sequence of literal text, lists in braces and ranges
+//! in brackets. Lists may nest (`{a,{b,c}}-1`) and contain ranges, and any
+//! character can be taken literally by escaping it with a backslash.
+//! Expansion produces every combination from left to right.
+
+use std::fmt;
+
+/// Syntax error in a host expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based column of the offending character
+    pub column: usize,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(column: usize, message: impl Into<String>) -> Self {
+        ParseError {
+            column,
+            message: message.into(),
+        }
+    }
+
+    /// Show the expression with a caret under the offending column
+    ///
+    /// # Arguments
+    /// * `expression` - Expression the error was found in
+    ///
+    /// # Returns
+    /// * `String` - Two lines: the expression and the caret
+    pub fn caret(&self, expression: &str) -> String {
+        format!("{}\n{}^", expression, " ".repeat(self.column - 1))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+/// Part of a parsed expression
+#[derive(Debug)]
+enum Node {
+    /// Text taken as is
+    Literal(String),
+    /// Alternatives written in braces, each a sequence of nodes
+    List(Vec<Vec<Node>>),
+    /// Values of a range written in brackets
+    Range(Vec<String>),
+}
+
+/// Recursive descent parser over the characters of an expression
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Parse nodes until the end of input or a character that closes the enclosing list
+    ///
+    /// # Arguments
+    /// * `nested` - Whether the sequence is inside braces, where `,` and `}` end it
+    ///
+    /// # Returns
+    /// * `Result<Vec<Node>, ParseError>` - Parsed nodes or the first syntax error
+    fn sequence(&mut self, nested: bool) -> Result<Vec<Node>, ParseError> {
+        let mut nodes = Vec::new();
+        let mut literal = String::new();
+        while let Some(&c) = self.chars.get(self.pos) {
+            match c {
+                ',' | '}' if nested => break,
+                '}' => return Err(ParseError::new(self.pos + 1, "unmatched '}'")),
+                ']' => return Err(ParseError::new(self.pos + 1, "unmatched ']'")),
+                '\\' => {
+                    self.pos += 1;
+                    match self.chars.get(self.pos) {
+                        Some(&escaped) => literal.push(escaped),
+                        None => {
+                            return Err(ParseError::new(self.pos, "nothing to escape after '\\'"))
+                        }
+                    }
+                    self.pos += 1;
+                }
+                '{' | '[' => {
+                    if !literal.is_empty() {
+                        nodes.push(Node::Literal(std::mem::take(&mut literal)));
+                    }
+                    nodes.push(if c == '{' {
+                        self.list()?
+                    } else {
+                        self.range()?
+                    });
+                }
+                c => {
+                    literal.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal));
+        }
+        Ok(nodes)
+    }
+
+    /// Parse a list starting at the current `{`
+    fn list(&mut self) -> Result<Node, ParseError> {
+        let open = self.pos;
+        self.pos += 1;
+        let mut alternatives = vec![self.sequence(true)?];
+        loop {
+            match self.chars.get(self.pos) {
+                Some(',') => {
+                    self.pos += 1;
+                    alternatives.push(self.sequence(true)?);
+                }
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return Err(ParseError::new(open + 1, "missing '}' for this '{'")),
+            }
+        }
+        if alternatives.len() == 1 && alternatives[0].is_empty() {
+            return Err(ParseError::new(open + 1, "empty list '{}'"));
+        }
+        Ok(Node::List(alternatives))
+    }
+
+    /// Parse a range starting at the current `[`
+    fn range(&mut self) -> Result<Node, ParseError> {
+        let open = self.pos;
+        let close = self.chars[open + 1..]
+            .iter()
+            .position(|c| matches!(c, ']' | '[' | '{' | '}'))
+            .map(|offset| open + 1 + offset)
+            .filter(|&close| self.chars[close] == ']')
+            .ok_or_else(|| ParseError::new(open + 1, "missing ']' for this '['"))?;
+        let spec: String = self.chars[open + 1..close].iter().collect();
+        self.pos = close + 1;
+        expand_range(&spec, open + 2).map(Node::Range)
+    }
+}
+
+/// Expand the inside of a range expression `[...]` to a list of strings
+///
+/// The range is a comma-separated list of items, each either a single value
+/// or `start:end[:step]`. Bounds are numbers or single letters, ranges may
+/// count down, and numbers written with leading zeros keep their width
+/// (`01:12` gives `01`, `02`, ... `12`).
+///
+/// # Arguments
+/// * `spec` - Range without the surrounding brackets, e.g. `1:3,7,9:11`
+/// * `column` - Column of the first character of `spec`, used for errors
+///
+/// # Returns
+/// * `Result<Vec<String>, ParseError>` - Expanded values, or the invalid item
+fn expand_range(spec: &str, column: usize) -> Result<Vec<String>, ParseError> {
+    let mut values = Vec::new();
+    let mut column = column;
+    for item in spec.split(',') {
+        let item_column = column;
+        column += item.chars().count() + 1;
+
+        let parts: Vec<&str> = item.split(':').collect();
+        let (start, end, step) = match parts[..] {
+            [value] => (value, value, None),
+            [start, end] => (start, end, None),
+            [start, end, step] => (start, end, Some(step)),
+            _ => {
+                return Err(ParseError::new(
+                    item_column,
+                    format!("too many colons in range item '{}'", item),
+                ))
+            }
+        };
+        let step = match step {
+            None => 1,
+            Some(step) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => step,
+                _ => {
+                    return Err(ParseError::new(
+                        item_column,
+                        format!("step '{}' must be a positive number", step),
+                    ))
+                }
+            },
+        };
+
+        let letter = |s: &str| {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+                _ => None,
+            }
+        };
+        match (
+            start.parse::<i64>(),
+            end.parse::<i64>(),
+            letter(start),
+            letter(end),
+        ) {
+            (Ok(low), Ok(high), _, _) => {
+                // Leading zeros on either bound fix the width of every value
+                let padded = |s: &str| s.trim_start_matches('-').len() > 1 && s.starts_with('0');
+                let width = if padded(start) || padded(end) {
+                    start.len().max(end.len())
+                } else {
+                    0
+                };
+                values.extend(
+                    step_through(low, high, step).map(|i| format!("{:0width$}", i, width = width)),
+                );
+            }
+            (_, _, Some(low), Some(high))
+                if low.is_ascii_lowercase() == high.is_ascii_lowercase() =>
+            {
+                values.extend(
+                    step_through(low as i64, high as i64, step)
+                        .map(|c| (c as u8 as char).to_string()),
+                );
+            }
+            _ => {
+                return Err(ParseError::new(
+                    item_column,
+                    format!(
+                        "'{}' is not a range of numbers or letters of the same case",
+                        item
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Count from `start` to `end` inclusive in either direction
+///
+/// # Arguments
+/// * `start` - First value
+/// * `end` - Last value, reached only if it lies on the step
+/// * `step` - Distance between consecutive values
+///
+/// # Returns
+/// * `impl Iterator<Item = i64>` - Values in order from `start` towards `end`
+fn step_through(start: i64, end: i64, step: usize) -> impl Iterator<Item = i64> {
+    // A step beyond i64 leaves the range right after `start` just like i64::MAX does
+    let step = i64::try_from(step).unwrap_or(i64::MAX);
+    let mut next = Some(start);
+    std::iter::from_fn(move || {
+        let current = next?;
+        // Stepping past the bounds of i64 ends the range instead of wrapping around
+        next = if start <= end {
+            current.checked_add(step).filter(|n| *n <= end)
+        } else {
+            current.checked_sub(step).filter(|n| *n >= end)
+        };
+        Some(current)
+    })
+}
+
+/// Produce every string described by a sequence of nodes
+fn expand_nodes(nodes: &[Node]) -> Vec<String> {
+    let mut results = vec![String::new()];
+    for node in nodes {
+        let values: Vec<String> = match node {
+            Node::Literal(text) => vec![text.clone()],
+            Node::List(alternatives) => alternatives
+                .iter()
+                .flat_map(|alternative| expand_nodes(alternative))
+                .collect(),
+            Node::Range(values) => values.clone(),
+        };
+        results = results
+            .iter()
+            .flat_map(|prefix| {
+                values
+                    .iter()
+                    .map(move |value| format!("{}{}", prefix, value))
+            })
+            .collect();
+    }
+    results
+}
+
+/// Expand a host expression into every string it describes
+///
+/// # Arguments
+/// * `expression` - Expression such as `web-[1:3].{prod,dev}`
+///
+/// # Returns
+/// * `Result<Vec<String>, ParseError>` - Expanded strings in order, or the syntax error
+pub fn expand(expression: &str) -> Result<Vec<String>, ParseError> {
+    let mut parser = Parser {
+        chars: expression.chars().collect(),
+        pos: 0,
+    };
+    let nodes = parser.sequence(false)?;
+    Ok(expand_nodes(&nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(spec: &str) -> Vec<String> {
+        expand_range(spec, 1).unwrap()
+    }
+
+    fn range_error(spec: &str) -> String {
+        expand_range(spec, 1).unwrap_err().message
+    }
+
+    #[test]
+    fn padded_range() {
+        assert_eq!(range("08:11"), ["08", "09", "10", "11"]);
+        assert_eq!(
+            range("1:010"),
+            ["001", "002", "003", "004", "005", "006", "007", "008", "009", "010"]
+        );
+        assert_eq!(range("0:2"), ["0", "1", "2"]);
+    }
+
+    #[test]
+    fn stepped_range() {
+        assert_eq!(range("0:10:5"), ["0", "5", "10"]);
+        assert_eq!(range("1:10:4"), ["1", "5", "9"]);
+    }
+
+    #[test]
+    fn descending_range() {
+        assert_eq!(range("3:1"), ["3", "2", "1"]);
+        assert_eq!(range("10:0:5"), ["10", "5", "0"]);
+        assert_eq!(range("-1:-3"), ["-1", "-2", "-3"]);
+    }
+
+    #[test]
+    fn letter_range() {
+        assert_eq!(range("a:c"), ["a", "b", "c"]);
+        assert_eq!(range("Z:X"), ["Z", "Y", "X"]);
+        assert_eq!(range("a:e:2"), ["a", "c", "e"]);
+    }
+
+    #[test]
+    fn comma_mixed_range() {
+        assert_eq!(range("1:3,7,9:11"), ["1", "2", "3", "7", "9", "10", "11"]);
+        assert_eq!(range("a,c:d,5"), ["a", "c", "d", "5"]);
+    }
+
+    #[test]
+    fn huge_steps_do_not_overflow() {
+        assert_eq!(
+            range("0:9223372036854775807:9223372036854775807"),
+            ["0", "9223372036854775807"]
+        );
+        assert_eq!(
+            range("-9223372036854775808:-9223372036854775807:2"),
+            ["-9223372036854775808"]
+        );
+        assert_eq!(range("5:1:18446744073709551615"), ["5"]);
+    }
+
+    #[test]
+    fn mixed_case_letters_are_rejected() {
+        assert_eq!(
+            range_error("a:C"),
+            "'a:C' is not a range of numbers or letters of the same case"
+        );
+    }
+
+    #[test]
+    fn zero_step_is_rejected() {
+        assert_eq!(range_error("1:5:0"), "step '0' must be a positive number");
+    }
+
+    #[test]
+    fn too_many_colons_are_rejected() {
+        assert_eq!(
+            range_error("1:5:1:2"),
+            "too many colons in range item '1:5:1:2'"
+        );
+    }
+
+    #[test]
+    fn errors_point_at_their_item() {
+        assert_eq!(expand_range("1,2:x", 4).unwrap_err().column, 6);
+    }
+
+    fn error(expression: &str) -> (usize, String) {
+        let e = expand(expression).unwrap_err();
+        (e.column, e.message)
+    }
+
+    #[test]
+    fn nested_lists() {
+        assert_eq!(expand("{a,{b,c}}-1").unwrap(), ["a-1", "b-1", "c-1"]);
+        assert_eq!(
+            expand("{web,db-{1,2}}.prod").unwrap(),
+            ["web.prod", "db-1.prod", "db-2.prod"]
+        );
+    }
+
+    #[test]
+    fn lists_and_ranges_combine_left_to_right() {
+        assert_eq!(
+            expand("n[1:2].{a,b}").unwrap(),
+            ["n1.a", "n1.b", "n2.a", "n2.b"]
+        );
+        assert_eq!(expand("{x[1:2],y}").unwrap(), ["x1", "x2", "y"]);
+    }
+
+    #[test]
+    fn escaped_characters_are_literal() {
+        assert_eq!(expand(r"web\[1\]").unwrap(), ["web[1]"]);
+        assert_eq!(expand(r"\{a\,b\}").unwrap(), ["{a,b}"]);
+        assert_eq!(expand(r"{a\,b,c}").unwrap(), ["a,b", "c"]);
+    }
+
+    #[test]
+    fn stray_closing_brace() {
+        assert_eq!(error("a}{b}"), (2, "unmatched '}'".to_string()));
+        assert_eq!(error("a]"), (2, "unmatched ']'".to_string()));
+    }
+
+    #[test]
+    fn missing_closing_bracket() {
+        assert_eq!(
+            error("web-[1:3"),
+            (5, "missing ']' for this '['".to_string())
+        );
+        assert_eq!(
+            error("web-[1:3{a}]"),
+            (5, "missing ']' for this '['".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_closing_brace() {
+        assert_eq!(
+            error("web-{a,b"),
+            (5, "missing '}' for this '{'".to_string())
+        );
+        assert_eq!(error("{a,{b}"), (1, "missing '}' for this '{'".to_string()));
+    }
+
+    #[test]
+    fn empty_list() {
+        assert_eq!(error("x{}"), (2, "empty list '{}'".to_string()));
+    }
+
+    #[test]
+    fn trailing_backslash() {
+        assert_eq!(
+            error("a\\"),
+            (2, "nothing to escape after '\\'".to_string())
+        );
+    }
+
+    #[test]
+    fn errors_inside_ranges_report_the_item_column() {
+        assert_eq!(
+            error("web-[1:x]"),
+            (
+                6,
+                "'1:x' is not a range of numbers or letters of the same case".to_string()
+            )
+        );
+        assert_eq!(error("{a,[1,0:2:0]}").0, 7);
+    }
+
+    #[test]
+    fn caret_points_at_the_column() {
+        let expression = "web-{a,b";
+        let e = expand(expression).unwrap_err();
+        assert_eq!(e.caret(expression), "web-{a,b\n    ^");
+        assert_eq!(e.to_string(), "column 5: missing '}' for this '{'");
+    }
+}
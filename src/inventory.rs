@@ -0,0 +1,744 @@
+//! Host source reading Ansible-style inventories
+//!
+//! INI, YAML and TOML inventories are loaded into the same model of hosts
+//! and groups. Group variables are inherited by their hosts, with deeper
+//! groups overriding their parents and host variables overriding groups, and
+//! the connection variables (`ansible_host`, `ansible_port`, `ansible_user`,
+//! `ansible_ssh_private_key_file`, and the `-o`/`-J` arguments of
+//! `ansible_ssh_common_args` and `ansible_ssh_extra_args`) are applied to the
+//! resulting [`Host`].
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::Host;
+
+/// Group every host belongs to
+const ALL: &str = "all";
+
+/// Group of hosts that are not listed in any other group
+const UNGROUPED: &str = "ungrouped";
+
+/// A group as written in the inventory
+#[derive(Debug, Default)]
+struct Group {
+    /// Hosts listed directly in the group
+    hosts: Vec<String>,
+    /// Names of child groups
+    children: Vec<String>,
+    /// Variables applied to every member
+    vars: BTreeMap<String, String>,
+}
+
+/// Hosts and groups loaded from an inventory file
+#[derive(Debug, Default)]
+pub struct Inventory {
+    /// Hosts in order of first appearance, with their own variables
+    hosts: Vec<(String, BTreeMap<String, String>)>,
+    /// Groups by name
+    groups: BTreeMap<String, Group>,
+}
+
+impl Inventory {
+    /// Load an inventory, picking the format from the file extension
+    ///
+    /// Files ending in `.yml`/`.yaml` are YAML, `.toml` is TOML and anything
+    /// else is read as an INI inventory, like Ansible's default `hosts` file.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the inventory file
+    ///
+    /// # Returns
+    /// * `Result<Inventory, String>` - Loaded inventory or a description of the problem
+    pub fn load(path: &Path) -> Result<Inventory, String> {
+        let content = read_to_string(path).map_err(|e| e.to_string())?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let mut inventory = Inventory::default();
+        match extension {
+            "yml" | "yaml" => {
+                let value: Value = serde_yaml::from_str(&content).map_err(|e| e.to_string())?;
+                inventory.load_structured(&value)?;
+            }
+            "toml" => {
+                let value: Value = toml::from_str(&content).map_err(|e| e.to_string())?;
+                inventory.load_structured(&value)?;
+            }
+            _ => inventory.load_ini(&content)?,
+        }
+        Ok(inventory)
+    }
+
+    /// Register a host, merging its variables with earlier mentions
+    fn add_host(&mut self, name: &str, vars: BTreeMap<String, String>) {
+        match self.hosts.iter_mut().find(|(host, _)| host == name) {
+            Some((_, existing)) => existing.extend(vars),
+            None => self.hosts.push((name.to_string(), vars)),
+        }
+    }
+
+    /// Access a group, creating it on first use
+    fn group(&mut self, name: &str) -> &mut Group {
+        self.groups.entry(name.to_string()).or_default()
+    }
+
+    /// Load an INI inventory
+    ///
+    /// Sections are `[group]`, `[group:vars]` and `[group:children]`; host lines
+    /// may use range patterns such as `web-[01:20]`, end in `:port` and carry
+    /// `key=value` variables.
+    ///
+    /// # Arguments
+    /// * `content` - Contents of the inventory file
+    ///
+    /// # Returns
+    /// * `Result<(), String>` - Ok, or the offending line
+    fn load_ini(&mut self, content: &str) -> Result<(), String> {
+        let mut section = (UNGROUPED.to_string(), "hosts".to_string());
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = match header.split_once(':') {
+                    Some((group, kind @ ("vars" | "children"))) => {
+                        (group.to_string(), kind.to_string())
+                    }
+                    Some(_) => {
+                        return Err(format!("line {}: unknown section [{}]", number + 1, header))
+                    }
+                    None => (header.to_string(), "hosts".to_string()),
+                };
+                self.group(&section.0);
+                continue;
+            }
+
+            let (group, kind) = (section.0.clone(), section.1.as_str());
+            match kind {
+                "vars" => {
+                    let (key, value) = line
+                        .split_once('=')
+                        .ok_or_else(|| format!("line {}: expected key=value", number + 1))?;
+                    self.group(&group)
+                        .vars
+                        .insert(key.trim().to_string(), unquote(value.trim()));
+                }
+                "children" => self.group(&group).children.push(line.to_string()),
+                _ => {
+                    // Values may be quoted to contain spaces, like ansible_ssh_common_args
+                    let fields = split_words(line);
+                    let mut fields = fields.iter();
+                    let pattern = fields.next().map(String::as_str).unwrap_or_default();
+                    let (pattern, port) = split_port(pattern);
+                    let mut vars = BTreeMap::new();
+                    for field in fields {
+                        let (key, value) = field.split_once('=').ok_or_else(|| {
+                            format!("line {}: expected key=value, got '{}'", number + 1, field)
+                        })?;
+                        vars.insert(key.to_string(), value.to_string());
+                    }
+                    // An explicit ansible_port on the same line wins over `host:port`
+                    if let Some(port) = port {
+                        vars.entry("ansible_port".to_string())
+                            .or_insert_with(|| port.to_string());
+                    }
+                    let names: Vec<String> = if pattern.contains('[') {
+                        crate::expand_string(pattern)
+                            .map_err(|e| format!("line {}: {} in '{}'", number + 1, e, pattern))?
+                            .into_iter()
+                            .map(|host| host.name)
+                            .collect()
+                    } else {
+                        vec![pattern.to_string()]
+                    };
+                    for name in names {
+                        self.add_host(&name, vars.clone());
+                        self.group(&group).hosts.push(name);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a YAML or TOML inventory
+    ///
+    /// Top-level keys are groups (usually just `all`), each with optional
+    /// `hosts`, `vars` and `children` tables nested the same way.
+    ///
+    /// # Arguments
+    /// * `value` - Parsed inventory document
+    ///
+    /// # Returns
+    /// * `Result<(), String>` - Ok, or a description of the malformed part
+    fn load_structured(&mut self, value: &Value) -> Result<(), String> {
+        let groups = match value {
+            Value::Object(groups) => groups,
+            Value::Null => return Ok(()),
+            _ => return Err("expected a mapping of groups at the top level".to_string()),
+        };
+        for (name, group) in groups {
+            self.load_group(name, group)?;
+        }
+        Ok(())
+    }
+
+    /// Load one group of a structured inventory together with its children
+    fn load_group(&mut self, name: &str, value: &Value) -> Result<(), String> {
+        self.group(name);
+        let group = match value {
+            Value::Object(group) => group,
+            Value::Null => return Ok(()),
+            _ => return Err(format!("group '{}' must be a mapping", name)),
+        };
+
+        match group.get("hosts") {
+            Some(Value::Object(hosts)) => {
+                for (host, vars) in hosts {
+                    self.add_host(host, to_vars(vars));
+                    self.group(name).hosts.push(host.clone());
+                }
+            }
+            Some(Value::Array(hosts)) => {
+                for host in hosts.iter().filter_map(Value::as_str) {
+                    self.add_host(host, BTreeMap::new());
+                    self.group(name).hosts.push(host.to_string());
+                }
+            }
+            Some(Value::Null) | None => (),
+            Some(_) => return Err(format!("hosts of group '{}' must be a mapping", name)),
+        }
+
+        if let Some(vars) = group.get("vars") {
+            let vars = to_vars(vars);
+            self.group(name).vars.extend(vars);
+        }
+
+        match group.get("children") {
+            Some(Value::Object(children)) => {
+                for (child, value) in children {
+                    self.group(name).children.push(child.clone());
+                    self.load_group(child, value)?;
+                }
+            }
+            // The TOML inventory plugin also allows a plain list of group names
+            Some(Value::Array(children)) => {
+                for child in children.iter().filter_map(Value::as_str) {
+                    self.group(name).children.push(child.to_string());
+                }
+            }
+            Some(Value::Null) | None => (),
+            Some(_) => return Err(format!("children of group '{}' must be a mapping", name)),
+        }
+        Ok(())
+    }
+
+    /// Collect the names of all hosts in a group and its descendants
+    ///
+    /// # Arguments
+    /// * `name` - Group name
+    ///
+    /// # Returns
+    /// * `Option<HashSet<String>>` - Member hostnames, or None if the group doesn't exist
+    fn members(&self, name: &str) -> Option<HashSet<String>> {
+        if name == ALL {
+            return Some(self.hosts.iter().map(|(host, _)| host.clone()).collect());
+        }
+        if name == UNGROUPED {
+            let grouped: HashSet<&String> = self
+                .groups
+                .iter()
+                .filter(|(group, _)| *group != UNGROUPED && *group != ALL)
+                .flat_map(|(_, group)| group.hosts.iter())
+                .collect();
+            return Some(
+                self.hosts
+                    .iter()
+                    .map(|(host, _)| host)
+                    .filter(|host| !grouped.contains(host))
+                    .cloned()
+                    .collect(),
+            );
+        }
+
+        self.groups.get(name)?;
+        let mut members = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![name];
+        while let Some(current) = pending.pop() {
+            if !visited.insert(current) {
+                continue;
+            }
+            if let Some(group) = self.groups.get(current) {
+                members.extend(group.hosts.iter().cloned());
+                pending.extend(group.children.iter().map(String::as_str));
+            }
+        }
+        Some(members)
+    }
+
+    /// Distance of a group from the top of the hierarchy, used for variable precedence
+    fn depth(&self, name: &str) -> usize {
+        let mut depth = 0;
+        let mut level: Vec<&str> = vec![name];
+        let mut visited = HashSet::new();
+        loop {
+            let parents: Vec<&str> = self
+                .groups
+                .iter()
+                .filter(|(parent, group)| {
+                    !visited.contains(parent.as_str())
+                        && group.children.iter().any(|c| level.contains(&c.as_str()))
+                })
+                .map(|(parent, _)| parent.as_str())
+                .collect();
+            if parents.is_empty() {
+                return depth;
+            }
+            visited.extend(parents.iter().copied());
+            level = parents;
+            depth += 1;
+        }
+    }
+
+    /// Build the final host with inherited variables applied
+    ///
+    /// # Arguments
+    /// * `name` - Inventory hostname
+    /// * `own_vars` - Variables set on the host itself
+    ///
+    /// # Returns
+    /// * `Host` - Host with connection settings and all variables
+    fn build_host(&self, name: &str, own_vars: &BTreeMap<String, String>) -> Host {
+        let mut groups: Vec<(usize, &String)> = self
+            .groups
+            .keys()
+            .filter(|group| self.members(group).is_some_and(|m| m.contains(name)))
+            .map(|group| (self.depth(group), group))
+            .collect();
+        groups.sort();
+
+        let mut vars = self
+            .groups
+            .get(ALL)
+            .map(|all| all.vars.clone())
+            .unwrap_or_default();
+        for (_, group) in groups.into_iter().filter(|(_, group)| *group != ALL) {
+            vars.extend(self.groups[group].vars.clone());
+        }
+        vars.extend(own_vars.clone());
+
+        let lookup = |keys: &[&str]| keys.iter().find_map(|key| vars.get(*key).cloned());
+        let mut host = Host {
+            name: name.to_string(),
+            address: lookup(&["ansible_host", "ansible_ssh_host"]),
+            user: lookup(&["ansible_user", "ansible_ssh_user", "user"]),
+            port: lookup(&["ansible_port", "ansible_ssh_port", "port"])
+                .and_then(|p| p.parse().ok()),
+            identity: lookup(&["ansible_ssh_private_key_file"]).map(PathBuf::from),
+            vars: vars.clone(),
+            ..Default::default()
+        };
+        for key in ["ansible_ssh_common_args", "ansible_ssh_extra_args"] {
+            if let Some(args) = vars.get(key) {
+                apply_ssh_args(&mut host, args);
+            }
+        }
+        host
+    }
+
+    /// Select hosts with an expression given via -e
+    ///
+    /// Expressions starting with `@` are Ansible-style patterns: terms separated
+    /// by `:` naming groups or hosts, where `&term` intersects and `!term`
+    /// excludes (`@prod:&eu:!canary`). Any other expression is a regular
+    /// expression matched against inventory hostnames.
+    ///
+    /// # Arguments
+    /// * `expression` - Selection expression
+    ///
+    /// # Returns
+    /// * `Result<Vec<Host>, String>` - Selected hosts in inventory order, or the reason the expression is invalid
+    pub fn select(&self, expression: &str) -> Result<Vec<Host>, String> {
+        let selected: HashSet<String> = match expression.strip_prefix('@') {
+            Some(pattern) => self.select_pattern(pattern)?,
+            None => {
+                let re = Regex::new(expression).map_err(|e| e.to_string())?;
+                self.hosts
+                    .iter()
+                    .map(|(host, _)| host)
+                    .filter(|host| re.is_match(host))
+                    .cloned()
+                    .collect()
+            }
+        };
+
+        Ok(self
+            .hosts
+            .iter()
+            .filter(|(host, _)| selected.contains(host))
+            .map(|(host, vars)| self.build_host(host, vars))
+            .collect())
+    }
+
+    /// Evaluate the terms of an `@` pattern into a set of hostnames
+    ///
+    /// Like Ansible, unions are taken first, then `&` intersections and then
+    /// `!` exclusions, whatever order they are written in. A pattern made of
+    /// intersections and exclusions only starts from all hosts.
+    ///
+    /// # Arguments
+    /// * `pattern` - Pattern without the leading `@`
+    ///
+    /// # Returns
+    /// * `Result<HashSet<String>, String>` - Selected hostnames, or the unknown term
+    fn select_pattern(&self, pattern: &str) -> Result<HashSet<String>, String> {
+        let mut unions = Vec::new();
+        let mut intersections = Vec::new();
+        let mut exclusions = Vec::new();
+        for term in pattern.split(':') {
+            let (terms, name) = match term.chars().next() {
+                Some('&') => (&mut intersections, &term[1..]),
+                Some('!') => (&mut exclusions, &term[1..]),
+                _ => (&mut unions, term),
+            };
+            let members = match self.members(name) {
+                Some(members) => members,
+                None if self.hosts.iter().any(|(host, _)| host == name) => {
+                    HashSet::from([name.to_string()])
+                }
+                None => return Err(format!("unknown group or host '{}'", name)),
+            };
+            terms.push(members);
+        }
+
+        let mut selected: HashSet<String> = if unions.is_empty() {
+            self.members(ALL).unwrap_or_default()
+        } else {
+            unions.into_iter().flatten().collect()
+        };
+        for members in intersections {
+            selected.retain(|host| members.contains(host));
+        }
+        for members in exclusions {
+            selected.retain(|host| !members.contains(host));
+        }
+        Ok(selected)
+    }
+}
+
+/// Apply the `-o` options and `-J` jump host of an ssh argument string to a host
+///
+/// # Arguments
+/// * `host` - Host receiving the settings
+/// * `args` - Value of `ansible_ssh_common_args` or `ansible_ssh_extra_args`
+fn apply_ssh_args(host: &mut Host, args: &str) {
+    let mut words = split_words(args).into_iter();
+    while let Some(word) = words.next() {
+        // Both `-o value` and `-ovalue` are accepted, like ssh does
+        let (flag, value) = match word.get(..2) {
+            Some(flag @ ("-o" | "-J")) if word.len() > 2 => {
+                (flag.to_string(), word[2..].to_string())
+            }
+            Some("-o" | "-J") => match words.next() {
+                Some(value) => (word, value),
+                None => break,
+            },
+            _ => {
+                warn!(
+                    "Ignoring ssh argument '{}' of {}, only -o and -J are supported",
+                    word, host.name
+                );
+                continue;
+            }
+        };
+        if flag == "-o" {
+            host.ssh_options.push(value);
+        } else if host.jump_host.is_none() {
+            host.jump_host = Some(value);
+        }
+    }
+}
+
+/// Split a trailing `:port` off an INI host pattern
+///
+/// Colons inside range brackets (`web-[1:2]:2222`) belong to the pattern, and
+/// patterns with several colons outside brackets are IPv6 addresses.
+///
+/// # Arguments
+/// * `pattern` - Host pattern as written in the inventory
+///
+/// # Returns
+/// * `(&str, Option<u16>)` - Pattern without the port, and the port if there was one
+fn split_port(pattern: &str) -> (&str, Option<u16>) {
+    let mut depth = 0;
+    let mut colons = Vec::new();
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ':' if depth == 0 => colons.push(i),
+            _ => (),
+        }
+    }
+    match colons[..] {
+        [i] => match pattern[i + 1..].parse() {
+            Ok(port) => (&pattern[..i], Some(port)),
+            Err(_) => (pattern, None),
+        },
+        _ => (pattern, None),
+    }
+}
+
+/// Split a command-line string into words, honouring single and double quotes
+fn split_words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Remove matching single or double quotes around an INI value
+fn unquote(value: &str) -> String {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner.to_string();
+        }
+    }
+    value.to_string()
+}
+
+/// Flatten a mapping of variables into strings
+fn to_vars(value: &Value) -> BTreeMap<String, String> {
+    match value {
+        Value::Object(vars) => vars
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                (key.clone(), value)
+            })
+            .collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ini(content: &str) -> Inventory {
+        let mut inventory = Inventory::default();
+        inventory.load_ini(content).unwrap();
+        inventory
+    }
+
+    fn yaml(content: &str) -> Inventory {
+        let mut inventory = Inventory::default();
+        let value: Value = serde_yaml::from_str(content).unwrap();
+        inventory.load_structured(&value).unwrap();
+        inventory
+    }
+
+    fn toml(content: &str) -> Inventory {
+        let mut inventory = Inventory::default();
+        let value: Value = toml::from_str(content).unwrap();
+        inventory.load_structured(&value).unwrap();
+        inventory
+    }
+
+    /// Names of the hosts an expression selects, in inventory order
+    fn names(inventory: &Inventory, expression: &str) -> Vec<String> {
+        inventory
+            .select(expression)
+            .unwrap()
+            .into_iter()
+            .map(|host| host.name)
+            .collect()
+    }
+
+    /// The single host an expression selects
+    fn host(inventory: &Inventory, name: &str) -> Host {
+        inventory.select(&format!("@{}", name)).unwrap().remove(0)
+    }
+
+    #[test]
+    fn ini_hosts_ranges_and_ports() {
+        let inventory = ini("
+            solo ansible_host=10.0.0.9
+
+            [db]
+            db1:5309 ansible_user=postgres
+            db2:5309 ansible_port=5310
+
+            [web]
+            web-[1:2]:2222 ansible_ssh_common_args='-o ProxyCommand=\"nc %h %p\" -J bastion'
+            2001:db8::1
+        ");
+        assert_eq!(
+            names(&inventory, "@all"),
+            ["solo", "db1", "db2", "web-1", "web-2", "2001:db8::1"]
+        );
+        assert_eq!(names(&inventory, "@ungrouped"), ["solo"]);
+        assert_eq!(
+            host(&inventory, "solo").address.as_deref(),
+            Some("10.0.0.9")
+        );
+
+        let db1 = host(&inventory, "db1");
+        assert_eq!(db1.port, Some(5309));
+        assert_eq!(db1.user.as_deref(), Some("postgres"));
+        assert_eq!(host(&inventory, "db2").port, Some(5310));
+
+        let web = host(&inventory, "web-2");
+        assert_eq!(web.port, Some(2222));
+        assert_eq!(web.ssh_options, ["ProxyCommand=nc %h %p"]);
+        assert_eq!(web.jump_host.as_deref(), Some("bastion"));
+        assert_eq!(inventory.select("^2001:").unwrap()[0].port, None);
+    }
+
+    #[test]
+    fn ini_rejects_malformed_lines() {
+        let mut inventory = Inventory::default();
+        let e = inventory.load_ini("[web:hosts]\n").unwrap_err();
+        assert_eq!(e, "line 1: unknown section [web:hosts]");
+        let e = inventory.load_ini("[web]\nweb-1 port\n").unwrap_err();
+        assert_eq!(e, "line 2: expected key=value, got 'port'");
+    }
+
+    #[test]
+    fn structured_inventories() {
+        let from_yaml = yaml(
+            "
+            all:
+              hosts:
+                solo:
+              children:
+                web:
+                  hosts:
+                    web-1:
+                      ansible_port: 2222
+                    web-2:
+                  vars:
+                    env: prod
+        ",
+        );
+        let from_toml = toml(
+            r#"
+            [all.hosts.solo]
+
+            [web.hosts.web-1]
+            ansible_port = 2222
+
+            [web.hosts.web-2]
+
+            [web.vars]
+            env = "prod"
+        "#,
+        );
+        for inventory in [from_yaml, from_toml] {
+            assert_eq!(names(&inventory, "@all"), ["solo", "web-1", "web-2"]);
+            assert_eq!(names(&inventory, "@web"), ["web-1", "web-2"]);
+            assert_eq!(host(&inventory, "web-1").port, Some(2222));
+            assert_eq!(host(&inventory, "web-2").vars["env"], "prod");
+        }
+    }
+
+    #[test]
+    fn variable_precedence() {
+        let inventory = ini("
+            [all:vars]
+            env=all
+            tier=all
+            ansible_user=deploy
+
+            [prod]
+            web-1 env=host
+
+            [eu]
+            web-1
+            web-2
+
+            [prod:children]
+            eu
+
+            [prod:vars]
+            env=prod
+            tier=prod
+
+            [eu:vars]
+            tier=eu
+        ");
+        // Host variables beat groups, and child groups beat their parents
+        let web1 = host(&inventory, "web-1");
+        assert_eq!(web1.vars["env"], "host");
+        assert_eq!(web1.vars["tier"], "eu");
+        assert_eq!(web1.user.as_deref(), Some("deploy"));
+        let web2 = host(&inventory, "web-2");
+        assert_eq!(web2.vars["env"], "prod");
+        assert_eq!(web2.vars["tier"], "eu");
+    }
+
+    #[test]
+    fn patterns() {
+        let inventory = ini("
+            [prod]
+            web-[1:4]
+            db-1
+
+            [eu]
+            web-[1:2]
+            db-1
+
+            [canary]
+            web-1
+        ");
+        assert_eq!(names(&inventory, "@prod:&eu:!canary"), ["web-2", "db-1"]);
+        // Intersections and exclusions apply after all unions, wherever they are written
+        assert_eq!(names(&inventory, "@!canary:eu:&prod"), ["web-2", "db-1"]);
+        assert_eq!(names(&inventory, "@&eu:prod:!db-1"), ["web-1", "web-2"]);
+        assert_eq!(names(&inventory, "@canary:db-1"), ["web-1", "db-1"]);
+        assert_eq!(names(&inventory, "@!eu"), ["web-3", "web-4"]);
+        assert_eq!(names(&inventory, "^web-[34]$"), ["web-3", "web-4"]);
+        assert_eq!(
+            inventory.select("@prod:!nope").unwrap_err(),
+            "unknown group or host 'nope'"
+        );
+    }
+}
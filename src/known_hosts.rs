@@ -0,0 +1,166 @@
+//! Host source reading server names from OpenSSH known_hosts files
+//!
+//! Each line holds an optional marker, a comma-separated list of host
+//! patterns, the key type and the key. Only plain names are usable as hosts:
+//! hashed names can't be reversed and wildcard patterns don't name a host.
+
+use log::warn;
+
+use crate::Host;
+
+/// Port ssh uses when a known_hosts entry doesn't specify one
+const SSH_PORT: u16 = 22;
+
+/// Parse the contents of a known_hosts file into hosts
+///
+/// # Arguments
+/// * `content` - Contents of a known_hosts file
+///
+/// # Returns
+/// * `Vec<Host>` - Unique hosts in order of appearance
+pub fn parse_known_hosts(content: &str) -> Vec<Host> {
+    let mut hosts: Vec<Host> = Vec::new();
+    let mut hashed = 0;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // @cert-authority and @revoked lines describe keys, not hosts
+        let names = match line.split_whitespace().next() {
+            Some(names) if !names.starts_with('@') => names,
+            _ => continue,
+        };
+
+        for pattern in names.split(',') {
+            if pattern.starts_with('|') {
+                hashed += 1;
+                continue;
+            }
+            if let Some(host) = parse_pattern(pattern) {
+                if !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+            }
+        }
+    }
+
+    if hashed > 0 {
+        warn!(
+            "Skipped {} hashed known_hosts entr{} (HashKnownHosts), their names can't be recovered",
+            hashed,
+            if hashed == 1 { "y" } else { "ies" }
+        );
+    }
+    hosts
+}
+
+/// Turn a single host pattern into a host
+///
+/// # Arguments
+/// * `pattern` - Name, address or `[name]:port` taken from a known_hosts line
+///
+/// # Returns
+/// * `Option<Host>` - Host, or None for wildcard, negated and malformed patterns
+fn parse_pattern(pattern: &str) -> Option<Host> {
+    if pattern.is_empty() || pattern.contains(['*', '?', '!']) {
+        return None;
+    }
+
+    // Non-standard ports are written as [name]:port
+    let (name, port) = match pattern.strip_prefix('[') {
+        Some(rest) => {
+            let (name, port) = rest.split_once("]:")?;
+            (name, Some(port.parse::<u16>().ok()?))
+        }
+        None => (pattern, None),
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(Host {
+        name: name.to_string(),
+        port: port.filter(|port| *port != SSH_PORT),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names and ports of the parsed hosts, in order
+    fn parse(content: &str) -> Vec<(String, Option<u16>)> {
+        parse_known_hosts(content)
+            .into_iter()
+            .map(|host| (host.name, host.port))
+            .collect()
+    }
+
+    fn host(name: &str, port: Option<u16>) -> (String, Option<u16>) {
+        (name.to_string(), port)
+    }
+
+    #[test]
+    fn comma_separated_names() {
+        assert_eq!(
+            parse("host1,10.0.0.1 ssh-ed25519 AAAA"),
+            vec![host("host1", None), host("10.0.0.1", None)]
+        );
+    }
+
+    #[test]
+    fn bracketed_ports() {
+        assert_eq!(
+            parse("[host]:2222 ssh-ed25519 AAAA\n[other]:22 ssh-ed25519 AAAA"),
+            vec![host("host", Some(2222)), host("other", None)]
+        );
+    }
+
+    #[test]
+    fn malformed_ports_are_skipped() {
+        assert_eq!(parse("[host]:ssh,[]:22,[x ssh-ed25519 AAAA"), vec![]);
+    }
+
+    #[test]
+    fn markers_are_skipped() {
+        let content = "@cert-authority *.example.com ssh-rsa AAAA\n\
+                       @revoked bad.example.com ssh-rsa AAAA\n\
+                       good.example.com ssh-rsa AAAA";
+        assert_eq!(parse(content), vec![host("good.example.com", None)]);
+    }
+
+    #[test]
+    fn hashed_names_are_skipped() {
+        assert_eq!(
+            parse("|1|c2FsdA==|aGFzaA== ssh-ed25519 AAAA\nplain ssh-ed25519 AAAA"),
+            vec![host("plain", None)]
+        );
+    }
+
+    #[test]
+    fn wildcard_and_negated_patterns_are_skipped() {
+        assert_eq!(
+            parse("*.example.com,web?,!db,app ssh-ed25519 AAAA"),
+            vec![host("app", None)]
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let content = "# managed by puppet\n\n   \n  # indented comment\nhost ssh-ed25519 AAAA\n";
+        assert_eq!(parse(content), vec![host("host", None)]);
+    }
+
+    #[test]
+    fn duplicates_are_removed() {
+        let content = "a,b ssh-ed25519 AAAA\nb,a ssh-rsa AAAA\n[a]:2222 ssh-rsa AAAA\na ecdsa AAAA";
+        assert_eq!(
+            parse(content),
+            vec![host("a", None), host("b", None), host("a", Some(2222))]
+        );
+    }
+}
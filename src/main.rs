@@ -1,23 +1,45 @@
 extern crate log;
 
-use std::fs::read_to_string;
+mod expression;
+mod inventory;
+mod known_hosts;
+#[cfg(feature = "native-ssh")]
+mod native;
+mod ssh_config;
+mod transport;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::fs::{self, read_to_string, File};
 use std::hash::Hash;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, Read, Write};
 use std::net::IpAddr;
-use std::process::{self, Command, Stdio};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use colored::*;
 use dns_lookup::lookup_host;
 use env_logger::Env;
+use expression::ParseError;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use log::{error, info, warn};
 use question::{Answer, Question};
 use rayon::prelude::*;
 use regex::Regex;
+use serde::Serialize;
+use serde_json::json;
+use transport::{
+    DockerTransport, KubectlTransport, LocalTransport, SshTransport, Transfer, Transport,
+};
 
 // Global state to track the currently open block
 lazy_static! {
@@ -28,9 +50,9 @@ lazy_static! {
 #[derive(Parser, Debug)]
 #[command(author = "AB ab@hexor.ru", version, about = "Parallel SSH executor in Rust", long_about = None)]
 struct Args {
-    /// Username for SSH connections (defaults to current system user)
-    #[arg(short = 'u', short_alias = 'l', long, default_value_t = whoami::username())]
-    username: String,
+    /// Username for SSH connections (defaults to ssh_config's User or the current system user)
+    #[arg(short = 'u', short_alias = 'l', long)]
+    username: Option<String>,
 
     /// Flag to use known_hosts file for server discovery instead of pattern expansion
     #[arg(
@@ -40,19 +62,170 @@ struct Args {
     )]
     known_hosts: bool,
 
+    /// known_hosts files to read instead of the default ones
+    #[arg(
+        long = "known-hosts-file",
+        value_name = "PATH",
+        requires = "known_hosts",
+        help = "Read servers from this known_hosts file (repeatable, defaults to ~/.ssh/known_hosts and /etc/ssh/ssh_known_hosts; UserKnownHostsFile and GlobalKnownHostsFile from ssh_config are not read)"
+    )]
+    known_hosts_files: Vec<PathBuf>,
+
+    /// Flag to use ssh_config host aliases for server discovery
+    #[arg(
+        long,
+        conflicts_with = "known_hosts",
+        help = "Use Host aliases from ~/.ssh/config (or --ssh-config-file) to build servers list instead of string expansion."
+    )]
+    ssh_config: bool,
+
+    /// Ansible-style inventory used for server discovery
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["known_hosts", "ssh_config"],
+        help = "Select servers from an Ansible inventory (INI, YAML or TOML); -e takes @group patterns or regexes"
+    )]
+    inventory: Option<PathBuf>,
+
     /// Server name patterns with expansion syntax
     /// Examples: 'web-[1:12]-io-{prod,dev}' expands to multiple servers
     #[arg(
         short,
         long,
         num_args = 1..,
-        help = "Expression to build server list. List and range expansion are supported. Example: 'web-[1:12]-io-{prod,dev}'"
+        help = "Expression to build server list. List and range expansion are supported. Example: 'web-[1:12]-io-{prod,dev}'. Hosts may be written as [user@]host[:port]. Use - to read hosts from stdin"
     )]
     expression: Vec<String>,
 
+    /// File with one host or expression per line
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read hosts from a file, one per line; '#' starts a comment"
+    )]
+    hosts_file: Option<PathBuf>,
+
+    /// Patterns of servers to leave out, in the same syntax as --expression
+    #[arg(
+        short = 'x',
+        long,
+        value_name = "EXPRESSION",
+        help = "Exclude servers matching this expression (repeatable, same syntax as -e)"
+    )]
+    exclude: Vec<String>,
+
     /// Command to execute on each server
-    #[arg(short, long, help = "Command to execute on servers")]
-    command: String,
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["list", "script", "push", "pull"],
+        help = "Command to execute on servers"
+    )]
+    command: Option<String>,
+
+    /// Local script streamed to the interpreter on every server
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "command",
+        help = "Run a local script on servers by feeding it to --interpreter over stdin (- reads it from stdin)"
+    )]
+    script: Option<PathBuf>,
+
+    /// Number of hosts run alone before all others
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Run on the first N servers alone and only continue after they all succeed"
+    )]
+    canary: Option<u64>,
+
+    /// Size of the batches the hosts are run in
+    #[arg(
+        long,
+        value_name = "N|P%",
+        value_parser = parse_amount,
+        help = "Run on servers in batches of N servers or P% of them, one batch after another"
+    )]
+    serial: Option<Amount>,
+
+    /// Failures tolerated before the remaining hosts are skipped
+    #[arg(
+        long,
+        value_name = "N|P%",
+        value_parser = parse_amount,
+        help = "Stop starting new servers once more than N servers or P% of them failed"
+    )]
+    max_fail: Option<Amount>,
+
+    /// Send local stdin to the command on every server
+    #[arg(
+        long,
+        conflicts_with_all = ["script", "push", "pull"],
+        help = "Send data piped into rexec to the stdin of the command on every server. The input is read into memory completely before the first server starts"
+    )]
+    stdin: bool,
+
+    /// Upload a local path to every server
+    #[arg(
+        long,
+        value_name = "LOCAL:REMOTE",
+        value_parser = parse_push,
+        conflicts_with_all = ["command", "script", "pull"],
+        help = "Copy a local file or directory to REMOTE on every server"
+    )]
+    push: Option<Transfer>,
+
+    /// Download a remote path from every server
+    #[arg(
+        long,
+        value_name = "REMOTE:LOCAL_DIR",
+        value_parser = parse_pull,
+        conflicts_with_all = ["command", "script"],
+        help = "Copy REMOTE from every server into LOCAL_DIR/<host>/"
+    )]
+    pull: Option<Transfer>,
+
+    /// Remote command reading the script from its stdin
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        default_value = "bash -s",
+        requires = "script",
+        help = "Interpreter reading the script from stdin, e.g. 'python3 -'"
+    )]
+    interpreter: String,
+
+    /// Arguments passed to the script
+    #[arg(
+        last = true,
+        value_name = "ARGS",
+        requires = "script",
+        help = "Arguments for the script, given after --"
+    )]
+    script_args: Vec<String>,
+
+    /// Print the selected hosts and exit instead of running a command
+    #[arg(
+        long,
+        visible_alias = "dry-run",
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "plain",
+        help = "Print the final host list and exit without running anything"
+    )]
+    list: Option<ListFormat>,
+
+    /// Resolve hosts while listing them
+    #[arg(
+        long,
+        requires = "list",
+        help = "Resolve hosts via DNS in --list mode and include their addresses"
+    )]
+    resolve: bool,
 
     /// Display only exit codes without command output
     #[arg(long, default_value_t = false, help = "Show exit code ONLY")]
@@ -68,17 +241,473 @@ struct Args {
     noconfirm: bool,
 
     /// Maximum number of parallel SSH connections
-    #[arg(short, long, default_value_t = 100)]
+    #[arg(short, long, default_value_t = 100, value_parser = clap::value_parser!(i32).range(1..))]
     parallel: i32,
+
+    /// Kill the SSH session if the command runs longer than this
+    #[arg(
+        short,
+        long,
+        value_name = "SECONDS",
+        help = "Kill the command on a host after SECONDS and report it as TIMEOUT"
+    )]
+    timeout: Option<u64>,
+
+    /// SSH connection timeout, passed to ssh as ConnectTimeout
+    #[arg(long, value_name = "SECONDS", help = "SSH connection timeout")]
+    connect_timeout: Option<u64>,
+
+    /// When rexec itself should exit with a non-zero status
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = FailOn::Any,
+        help = "Exit non-zero when any host, all hosts or no host failed. Exit code 1 means the command failed, 3 means hosts were unreachable"
+    )]
+    fail_on: FailOn,
+
+    /// Output format for command results
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "Output format for command results"
+    )]
+    output: OutputFormat,
+
+    /// Group hosts with identical output and print every distinct output once
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "code",
+        help = "Print identical output of several hosts only once (text output only)"
+    )]
+    collate: bool,
+
+    /// Directory receiving per-host stdout, stderr and exit code files
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Write <host>.stdout, <host>.stderr and <host>.exitcode files into DIR. Combine with --code to keep the terminal quiet"
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// How commands are delivered to the hosts
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TransportKind::Ssh,
+        help = "How to reach the targets: ssh binary, in-process SSH client (requires the native-ssh feature), local shell, docker exec or kubectl exec"
+    )]
+    transport: TransportKind,
+
+    /// How unknown and changed SSH host keys are handled
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = HostKeyChecking::AcceptNew,
+        help = "Host key policy: reject unknown keys (strict), remember unknown keys (accept-new) or accept any key (off)"
+    )]
+    host_key_checking: HostKeyChecking,
+
+    /// Extra ssh options, passed to ssh as `-o KEY=VALUE`
+    #[arg(
+        short = 'o',
+        long = "ssh-option",
+        value_name = "KEY=VALUE",
+        value_parser = parse_ssh_option,
+        help = "Pass an option to ssh, e.g. -o ServerAliveInterval=10. Can be repeated"
+    )]
+    ssh_options: Vec<String>,
+
+    /// SSH port for hosts that don't specify one as host:port
+    #[arg(long, help = "SSH port for hosts without an explicit host:port")]
+    port: Option<u16>,
+
+    /// Private key used for authentication
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        help = "SSH identity (private key) file"
+    )]
+    identity: Option<PathBuf>,
+
+    /// Bastion host to connect through
+    #[arg(
+        short = 'J',
+        long,
+        value_name = "[USER@]HOST[:PORT]",
+        help = "Connect through a jump host (ssh -J)"
+    )]
+    jump_host: Option<String>,
+
+    /// Alternative ssh_config file
+    #[arg(
+        short = 'F',
+        long,
+        value_name = "FILE",
+        help = "Use a custom ssh_config file (ssh -F)"
+    )]
+    ssh_config_file: Option<PathBuf>,
+}
+
+/// Parse a `--push LOCAL:REMOTE` value
+///
+/// # Arguments
+/// * `s` - Raw value from the command line
+///
+/// # Returns
+/// * `Result<Transfer, String>` - Push transfer, or why the value is invalid
+fn parse_push(s: &str) -> Result<Transfer, String> {
+    match s.split_once(':') {
+        Some((local, remote)) if !local.is_empty() && !remote.is_empty() => Ok(Transfer::Push {
+            local: PathBuf::from(local),
+            remote: remote.to_string(),
+        }),
+        _ => Err("expected LOCAL:REMOTE".to_string()),
+    }
+}
+
+/// Parse a `--pull REMOTE:LOCAL_DIR` value
+///
+/// # Arguments
+/// * `s` - Raw value from the command line
+///
+/// # Returns
+/// * `Result<Transfer, String>` - Pull transfer, or why the value is invalid
+fn parse_pull(s: &str) -> Result<Transfer, String> {
+    match s.rsplit_once(':') {
+        Some((remote, local)) if !remote.is_empty() && !local.is_empty() => Ok(Transfer::Pull {
+            remote: remote.to_string(),
+            local: PathBuf::from(local),
+        }),
+        _ => Err("expected REMOTE:LOCAL_DIR".to_string()),
+    }
 }
 
+/// Validate a `--ssh-option` value
+///
+/// # Arguments
+/// * `s` - Raw option from the command line
+///
+/// # Returns
+/// * `Result<String, String>` - The option unchanged, or an explanation why it is invalid
+fn parse_ssh_option(s: &str) -> Result<String, String> {
+    match s.split_once('=') {
+        Some((key, _)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+            Ok(s.to_string())
+        }
+        _ => Err(format!("expected KEY=VALUE, got '{}'", s)),
+    }
+}
+
+/// Policy for verifying SSH host keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HostKeyChecking {
+    /// Only connect to hosts whose key is already known
+    Strict,
+    /// Remember keys of new hosts, refuse hosts whose key changed
+    AcceptNew,
+    /// Accept any host key
+    Off,
+}
+
+impl HostKeyChecking {
+    /// Value of ssh's StrictHostKeyChecking option implementing this policy
+    fn ssh_option(self) -> &'static str {
+        match self {
+            HostKeyChecking::Strict => "yes",
+            HostKeyChecking::AcceptNew => "accept-new",
+            HostKeyChecking::Off => "no",
+        }
+    }
+}
+
+/// Mechanism used to run commands on the targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TransportKind {
+    /// Spawn the system ssh client for every host
+    Ssh,
+    /// Use the built-in libssh2 client
+    Native,
+    /// Run the command in a local shell, once per target
+    Local,
+    /// Run the command inside docker containers named by the targets
+    Docker,
+    /// Run the command inside kubernetes pods named by the targets
+    Kubectl,
+}
+
+/// Policy for turning per-host failures into rexec's own exit status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FailOn {
+    /// Fail if at least one host failed
+    Any,
+    /// Fail only if every host failed
+    All,
+    /// Always exit with 0 once commands were run
+    None,
+}
+
+/// Format used to print command results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable blocks streamed as output arrives
+    Text,
+    /// A single JSON document printed once every host has finished
+    Json,
+    /// One JSON event per line, streamed as output arrives
+    Ndjson,
+}
+
+/// A number of hosts, either absolute or as a percentage of all hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Amount {
+    Count(usize),
+    Percent(usize),
+}
+
+impl Amount {
+    /// Number of hosts this amount stands for, rounding percentages up
+    fn of(self, total: usize) -> usize {
+        match self {
+            Amount::Count(count) => count,
+            Amount::Percent(percent) => (total * percent).div_ceil(100),
+        }
+    }
+
+    /// Whether `failed` hosts out of `total` are more than this amount allows
+    fn exceeded_by(self, failed: usize, total: usize) -> bool {
+        match self {
+            Amount::Count(count) => failed > count,
+            Amount::Percent(percent) => failed * 100 > total * percent,
+        }
+    }
+}
+
+/// Parse an `N` or `P%` value
+///
+/// # Arguments
+/// * `s` - Raw value from the command line
+///
+/// # Returns
+/// * `Result<Amount, String>` - Parsed amount, or why the value is invalid
+fn parse_amount(s: &str) -> Result<Amount, String> {
+    match s.strip_suffix('%') {
+        Some(percent) => match percent.parse::<usize>() {
+            Ok(percent) if percent <= 100 => Ok(Amount::Percent(percent)),
+            _ => Err("expected a percentage between 0% and 100%".to_string()),
+        },
+        None => s
+            .parse::<usize>()
+            .map(Amount::Count)
+            .map_err(|_| "expected a number of hosts or a percentage like 10%".to_string()),
+    }
+}
+
+/// Format of the host list printed by --list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    /// One host per line
+    Plain,
+    /// A JSON array of hosts with their connection settings
+    Json,
+    /// Hosts compressed back into range expressions
+    Ranges,
+}
+
+/// Exit status when the command failed or timed out on some hosts
+const EXIT_COMMAND_FAILED: i32 = 1;
+/// Exit status when some hosts could not be resolved or connected to
+const EXIT_UNREACHABLE: i32 = 3;
+/// Exit code reported by ssh itself when the connection fails
+const SSH_CONNECTION_ERROR: i32 = 255;
+
+/// Outcome of running a command on a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Outcome {
+    /// The command finished with the given exit code
+    Exited(i32),
+    /// The command was killed after exceeding the timeout
+    TimedOut,
+}
+
+/// Reason a command could not be run on a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostError {
+    /// The connection could not be established
+    Connect(String),
+    /// The server rejected every authentication method
+    Auth(String),
+    /// The host key differs from the known one
+    HostKey(String),
+    /// Any other failure
+    Other(String),
+}
+
+impl HostError {
+    /// Short machine-readable name of the failure category
+    fn kind(&self) -> &'static str {
+        match self {
+            HostError::Connect(_) => "connect_failed",
+            HostError::Auth(_) => "auth_failed",
+            HostError::HostKey(_) => "host_key_mismatch",
+            HostError::Other(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::Connect(e) => write!(f, "Connection failed: {}", e),
+            HostError::Auth(e) => write!(f, "Authentication failed: {}", e),
+            HostError::HostKey(e) => write!(f, "Host key mismatch: {}", e),
+            HostError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Output stream of the remote command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Result of running the command on a single host
+#[derive(Debug)]
+struct HostReport {
+    /// Hostname the command was run on
+    hostname: String,
+    /// Resolved IP address of the host, if the transport needs one
+    ip: Option<IpAddr>,
+    /// Command outcome or the error that prevented running it
+    result: Result<Outcome, HostError>,
+    /// Time spent on the host
+    duration: Duration,
+    /// Captured output lines (only filled for JSON output and collation)
+    output: Vec<(Stream, String)>,
+}
+
+impl HostReport {
+    /// Join all captured lines of one stream back into a single string
+    fn stream_text(&self, stream: Stream) -> String {
+        self.output
+            .iter()
+            .filter(|(s, _)| *s == stream)
+            .map(|(_, line)| format!("{}\n", line))
+            .collect()
+    }
+}
+
+/// Per-host output files written under `--output-dir`
+struct HostFiles {
+    /// Receives the standard output of the command
+    stdout: Mutex<File>,
+    /// Receives the standard error of the command
+    stderr: Mutex<File>,
+    /// Path of the file receiving the final exit code
+    exitcode: PathBuf,
+}
+
+impl HostFiles {
+    /// Create (or truncate) the output files of a host inside `dir`
+    fn create(dir: &Path, hostname: &str) -> io::Result<Self> {
+        // Hostnames never contain slashes, but don't let a stray one escape the directory
+        let name = hostname.replace('/', "_");
+        Ok(HostFiles {
+            stdout: Mutex::new(File::create(dir.join(format!("{}.stdout", name)))?),
+            stderr: Mutex::new(File::create(dir.join(format!("{}.stderr", name)))?),
+            exitcode: dir.join(format!("{}.exitcode", name)),
+        })
+    }
+
+    /// Append a line of output to the file of its stream
+    fn write_line(&self, stream: Stream, line: &str) {
+        let file = match stream {
+            Stream::Stdout => &self.stdout,
+            Stream::Stderr => &self.stderr,
+        };
+        if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+            error!("Failed to write output file: {}", e);
+        }
+    }
+
+    /// Record the outcome of the command once it has finished
+    fn write_outcome(&self, result: &Result<Outcome, HostError>) {
+        let content = match result {
+            Ok(Outcome::Exited(code)) => format!("{}\n", code),
+            Ok(Outcome::TimedOut) => "TIMEOUT\n".to_string(),
+            Err(e) => format!("ERROR {}\n", e),
+        };
+        if let Err(e) = fs::write(&self.exitcode, content) {
+            error!("Failed to write {}: {}", self.exitcode.display(), e);
+        }
+    }
+}
+
+/// System-wide known_hosts file read by ssh by default (GlobalKnownHostsFile)
+const GLOBAL_KNOWN_HOSTS: &str = "/etc/ssh/ssh_known_hosts";
+
 /// Host representation for both known_hosts entries and expanded patterns
+///
+/// Besides the name, a host may carry connection settings that override the
+/// command-line defaults for this host only.
 #[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
 struct Host {
     /// Hostname or IP address as a string
     name: String,
     /// Resolved IP address (if available)
     ip: Option<IpAddr>,
+    /// Real hostname to resolve when `name` is an alias
+    address: Option<String>,
+    /// Whether `name` is an ssh_config alias that ssh looks up by itself
+    ssh_alias: bool,
+    /// SSH username overriding --username
+    user: Option<String>,
+    /// SSH port overriding --port
+    port: Option<u16>,
+    /// Identity file overriding --identity
+    identity: Option<PathBuf>,
+    /// Jump host overriding --jump-host
+    jump_host: Option<String>,
+    /// Extra ssh options, applied before the ones from --ssh-option
+    ssh_options: Vec<String>,
+    /// Inventory variables of the host
+    vars: BTreeMap<String, String>,
+}
+
+/// Parse a host written as `[user@]name[:port]`
+///
+/// A colon is only treated as a port separator when it is the only one, so
+/// IPv6 addresses are kept intact.
+///
+/// # Arguments
+/// * `spec` - Host specification
+///
+/// # Returns
+/// * `Host` - Host with user and port overrides filled in
+fn parse_host_spec(spec: &str) -> Host {
+    let (user, rest) = match spec.rsplit_once('@') {
+        Some((user, rest)) if !user.is_empty() => (Some(user.to_string()), rest),
+        _ => (None, spec),
+    };
+    let (name, port) = match rest.split_once(':') {
+        Some((name, port)) if !port.contains(':') => match port.parse::<u16>() {
+            Ok(port) => (name, Some(port)),
+            Err(_) => (rest, None),
+        },
+        _ => (rest, None),
+    };
+    Host {
+        name: name.to_string(),
+        user,
+        port,
+        ..Default::default()
+    }
 }
 
 /// Find common domain suffix across all hostnames to simplify output display
@@ -157,274 +786,1296 @@ fn shorten_hostname(hostname: &str, common_suffix: &Option<String>) -> String {
     }
 }
 
-/// Read and parse the SSH known_hosts file to extract server names
-///
-/// # Returns
-/// * `Vec<Host>` - List of hosts found in the known_hosts file
-fn read_known_hosts() -> Vec<Host> {
-    let mut result: Vec<Host> = Vec::new();
+/// Home directory of the current user, taken from $HOME or the passwd entry
+fn home_dir() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) if !home.is_empty() => PathBuf::from(home),
+        _ => match passwd_home_dir() {
+            Some(home) => home,
+            None => {
+                error!("$HOME is not set and the home directory of the current user is unknown");
+                process::exit(1);
+            }
+        },
+    }
+}
 
-    // Read known_hosts file from the user's home directory
-    for line in read_to_string(format!("/home/{}/.ssh/known_hosts", whoami::username()))
-        .unwrap()
-        .lines()
-    {
-        let line = line.split(" ").collect::<Vec<&str>>();
-        let hostname = line[0];
-        result.push(Host {
-            name: hostname.to_string(),
-            ip: None,
-        })
+/// Home directory of the current user according to the passwd database
+fn passwd_home_dir() -> Option<PathBuf> {
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let status = unsafe {
+            libc::getpwuid_r(
+                libc::getuid(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        // The buffer holds the strings of the entry, retry with a larger one if they don't fit
+        if status == libc::ERANGE && buffer.len() < 1 << 20 {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if status != 0 || result.is_null() || passwd.pw_dir.is_null() {
+            return None;
+        }
+        let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+        return Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())));
     }
-    result
 }
 
-/// Expand a numeric range in the format [start:end] to a list of strings
+/// Keep the hosts whose name matches any of the regular expressions
 ///
 /// # Arguments
-/// * `start` - Starting number (inclusive)
-/// * `end` - Ending number (inclusive)
+/// * `hosts` - Candidate hosts from known_hosts or ssh_config
+/// * `expressions` - Regular expressions given with -e
 ///
 /// # Returns
-/// * `Vec<String>` - List of numbers as strings
-fn expand_range(start: i32, end: i32) -> Vec<String> {
-    (start..=end).map(|i| i.to_string()).collect()
+/// * `Vec<Host>` - Matching hosts, grouped by expression
+fn filter_hosts(hosts: &[Host], expressions: &[String]) -> Vec<Host> {
+    let mut all_hosts = Vec::new();
+    for expression in expressions.iter() {
+        let re = match Regex::new(expression) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Error parsing regex. {}", e);
+                process::exit(1);
+            }
+        };
+        let matched = hosts.iter().filter(|r| re.is_match(&r.name)).cloned();
+        all_hosts.extend(matched);
+    }
+    all_hosts
 }
 
-/// Expand a comma-separated list in the format {item1,item2,item3} to a list of strings
+/// Parse a newline-separated host list, dropping comments and blank lines
 ///
 /// # Arguments
-/// * `list` - Comma-separated string to expand
+/// * `content` - Contents of a hosts file or of stdin
 ///
 /// # Returns
-/// * `Vec<String>` - List of expanded items
-fn expand_list(list: &str) -> Vec<String> {
-    list.split(',').map(|s| s.to_string()).collect()
+/// * `Vec<String>` - One expression per non-empty line
+fn parse_hosts_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
 }
 
-/// Expand a server pattern string with range and list notation into individual hostnames
-///
-/// Supports two expansion types:
-/// - Range expansion: server-[1:5] → server-1, server-2, server-3, server-4, server-5
-/// - List expansion: server-{prod,dev} → server-prod, server-dev
+/// Gather the expressions given with -e, `-e -` and --hosts-file
 ///
 /// # Arguments
-/// * `s` - Pattern string to expand
+/// * `args` - Parsed command-line arguments
 ///
 /// # Returns
-/// * `Vec<Host>` - List of expanded Host objects
-fn expand_string(s: &str) -> Vec<Host> {
-    let mut hosts: Vec<Host> = Vec::new();
-    let mut result = vec![s.to_string()];
-
-    // First expand all range expressions [start:end]
-    while let Some(r) = result.iter().find(|s| s.contains('[')) {
-        let r = r.clone();
-        let start = r.find('[').unwrap();
-        let end = match r[start..].find(']') {
-            None => {
-                error!("Error parsing host expression. Wrong range expansion '[a:b]'");
+/// * `Vec<String>` - Expressions to select hosts with
+fn collect_expressions(args: &Args) -> Vec<String> {
+    let mut expressions = Vec::new();
+    let mut stdin_read = false;
+    for expression in &args.expression {
+        if expression != "-" {
+            expressions.push(expression.clone());
+        } else if !stdin_read {
+            stdin_read = true;
+            let mut content = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut content) {
+                error!("Failed to read hosts from stdin: {}", e);
                 process::exit(1);
             }
-            Some(s) => s + start,
-        };
-        let colon = match r[start..end].find(':') {
-            None => {
-                error!("Error parsing host expression. Missing colon in range expansion '[a:b]'");
-                process::exit(1);
-            }
-            Some(c) => c + start,
-        };
-        let low = r[start + 1..colon].parse::<i32>().unwrap();
-        let high = r[colon + 1..end].parse::<i32>().unwrap();
-        result.retain(|s| s != &r);
-        for val in expand_range(low, high) {
-            let new_str = format!("{}{}{}", &r[..start], val, &r[end + 1..]);
-            result.push(new_str);
+            expressions.extend(parse_hosts_list(&content));
         }
     }
-
-    // Then expand all list expressions {item1,item2}
-    while let Some(r) = result.iter().find(|s| s.contains('{')) {
-        let r = r.clone();
-        let start = r.find('{').unwrap();
-        let end = match r.find('}') {
-            None => {
-                error!("Error parsing host expression. Wrong range expansion '{{one,two}}'");
+    if let Some(path) = &args.hosts_file {
+        match read_to_string(path) {
+            Ok(content) => expressions.extend(parse_hosts_list(&content)),
+            Err(e) => {
+                error!("Failed to read hosts file {}: {}", path.display(), e);
                 process::exit(1);
             }
-            Some(s) => s,
-        };
-        let list = &r[start + 1..end];
-        result.retain(|s| s != &r);
-        for val in expand_list(list) {
-            let new_str = format!("{}{}{}", &r[..start], val, &r[end + 1..]);
-            result.push(new_str);
         }
     }
+    expressions
+}
 
-    // Convert all expanded strings to Host objects
-    for hostname in result {
-        hosts.push(Host {
-            name: hostname.to_string(),
-            ip: None,
-        })
-    }
-    hosts
+/// Whether the host list, the script or the command input is read from stdin
+fn reads_stdin(args: &Args) -> bool {
+    args.expression.iter().any(|e| e == "-")
+        || args.script.as_deref() == Some(Path::new("-"))
+        || args.stdin
 }
 
-/// Execute a command on a single host using the system SSH client
+/// Read all of stdin so it can be sent to every host
 ///
-/// This function runs an SSH command using the system's SSH client,
-/// capturing and displaying output in real-time with proper formatting.
-///
-/// # Arguments
-/// * `hostname` - Target server hostname
-/// * `username` - SSH username
-/// * `command` - Command to execute
-/// * `common_suffix` - Optional common suffix for hostname display formatting
-/// * `code_only` - Whether to display only exit codes
+/// The whole input is buffered before any host starts, so every host, including
+/// later --serial batches, receives the same data.
 ///
 /// # Returns
-/// * `Result<i32, String>` - Exit code on success or error message
-fn execute_ssh_command(
-    hostname: &str,
-    username: &str,
-    command: &str,
-    common_suffix: &Option<String>,
-    code_only: bool,
-) -> Result<i32, String> {
-    let display_name = shorten_hostname(hostname, common_suffix);
+/// * `Vec<u8>` - Data piped into rexec
+fn read_stdin() -> Vec<u8> {
+    let mut data = Vec::new();
+    if let Err(e) = io::stdin().read_to_end(&mut data) {
+        error!("Failed to read stdin: {}", e);
+        process::exit(1);
+    }
+    data
+}
 
-    // Build the SSH command with appropriate options
-    let mut ssh_cmd = Command::new("ssh");
-    ssh_cmd
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg("-o")
-        .arg("BatchMode=yes")
-        .arg(format!("{}@{}", username, hostname))
-        .arg(command)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
-
-    // Execute the command
-    let mut child = match ssh_cmd.spawn() {
-        Ok(child) => child,
-        Err(e) => return Err(format!("Failed to start SSH process: {}", e)),
+/// Read the script given with --script
+///
+/// # Arguments
+/// * `path` - Script file, or `-` for stdin
+///
+/// # Returns
+/// * `Vec<u8>` - Script contents
+fn read_script(path: &Path) -> Vec<u8> {
+    let script = if path == Path::new("-") {
+        let mut script = Vec::new();
+        io::stdin().read_to_end(&mut script).map(|_| script)
+    } else {
+        fs::read(path)
     };
+    match script {
+        Ok(script) => script,
+        Err(e) => {
+            error!("Failed to read script {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
 
-    // Function to handle output lines with proper block management
-    let handle_output = |line: String, display_name: &str, code_only: bool, is_stderr: bool| {
-        if !code_only {
-            let mut current_block = CURRENT_BLOCK.lock().unwrap();
-
-            // Check if we need to close the previous block and open a new one
-            match current_block.as_ref() {
-                Some(open_host) if open_host != display_name => {
-                    // Close the previous block
-                    println!("└ {} ┘", open_host.yellow());
-                    // Open new block
-                    println!("┌ {} ┐", display_name.yellow());
-                    *current_block = Some(display_name.to_string());
-                }
+/// Quote a word for the remote shell unless it is plainly safe
+fn shell_quote(word: &str) -> String {
+    lazy_static! {
+        static ref SAFE: Regex = Regex::new(r"^[A-Za-z0-9_./=:,@%+-]+$").unwrap();
+    }
+    if SAFE.is_match(word) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Build the remote command that runs a script read from stdin
+///
+/// # Arguments
+/// * `interpreter` - Interpreter command reading the script from stdin
+/// * `script_args` - Arguments passed on to the script
+///
+/// # Returns
+/// * `String` - Remote command line
+fn script_command(interpreter: &str, script_args: &[String]) -> String {
+    std::iter::once(interpreter.to_string())
+        .chain(script_args.iter().map(|arg| shell_quote(arg)))
+        .join(" ")
+}
+
+/// Placeholders every host can fill in, besides its variables
+const BUILTIN_PLACEHOLDERS: [&str; 6] = ["host", "short", "ip", "index", "user", "port"];
+
+/// Fill the per-host placeholders of a command
+///
+/// `{host}`, `{short}` (first label of the hostname), `{ip}`, `{index}`
+/// (1-based position in the host list), `{user}`, `{port}` and the variables
+/// of the selected hosts are replaced by their value for the host, quoted for
+/// the remote shell unless written as `{name:raw}`. Any other braces, such as
+/// `${VAR}` or awk's `BEGIN{exit}`, are left alone, and `{{name}}` is kept as
+/// the literal `{name}`.
+///
+/// # Arguments
+/// * `template` - Command as given on the command line
+/// * `host` - Host the command is run on
+/// * `index` - Zero-based position of the host in the list of hosts to run on
+/// * `username` - Username used for hosts that don't set their own
+/// * `variables` - Names of the variables set on any of the selected hosts
+///
+/// # Returns
+/// * `Result<String, Vec<String>>` - Command for this host, or the placeholders without a value
+fn render_command(
+    template: &str,
+    host: &Host,
+    index: usize,
+    username: &str,
+    variables: &HashSet<String>,
+) -> Result<String, Vec<String>> {
+    lazy_static! {
+        static ref PLACEHOLDER: Regex =
+            Regex::new(r"(\$?)\{(\{?)([A-Za-z_][A-Za-z0-9_]*)(:raw)?\}(\}?)").unwrap();
+    }
+
+    let value = |name: &str| -> Option<String> {
+        match name {
+            "host" => Some(host.name.clone()),
+            "short" => host.name.split('.').next().map(String::from),
+            "ip" => host.ip.map(|ip| ip.to_string()),
+            "index" => Some((index + 1).to_string()),
+            "user" => Some(host.user.as_deref().unwrap_or(username).to_string()),
+            "port" => host.port.map(|port| port.to_string()),
+            _ => host.vars.get(name).cloned(),
+        }
+    };
+
+    let mut missing: Vec<String> = Vec::new();
+    let rendered = PLACEHOLDER
+        .replace_all(template, |c: &regex::Captures| {
+            let (dollar, open, name, close) = (&c[1], &c[2], &c[3], &c[5]);
+            let raw = c.get(4).is_some();
+            // `${name}` belongs to the remote shell
+            if !dollar.is_empty() {
+                return c[0].to_string();
+            }
+            if !open.is_empty() && !close.is_empty() {
+                return c[0][1..c[0].len() - 1].to_string();
+            }
+            if !BUILTIN_PLACEHOLDERS.contains(&name) && !variables.contains(name) {
+                return c[0].to_string();
+            }
+            match value(name) {
+                // Values come from inventories and host lists, keep them one shell word
+                Some(value) if !raw => format!("{}{}{}", open, shell_quote(&value), close),
+                Some(value) => format!("{}{}{}", open, value, close),
                 None => {
-                    // Open new block
-                    println!("┌ {} ┐", display_name.yellow());
-                    *current_block = Some(display_name.to_string());
+                    if !missing.iter().any(|m| m == name) {
+                        missing.push(name.to_string());
+                    }
+                    c[0].to_string()
+                }
+            }
+        })
+        .into_owned();
+    if missing.is_empty() {
+        Ok(rendered)
+    } else {
+        Err(missing)
+    }
+}
+
+/// Ask the user to confirm, on the terminal if stdin is already in use
+///
+/// # Arguments
+/// * `args` - Parsed command-line arguments
+/// * `prompt` - Question to ask
+///
+/// # Returns
+/// * `bool` - Whether the user agreed
+fn confirm(args: &Args, prompt: &str) -> bool {
+    // Hosts or a script piped in leave stdin at EOF, so ask on the terminal instead
+    if reads_stdin(args) {
+        confirm_on_tty(prompt)
+    } else {
+        match Question::new(prompt).confirm() {
+            Answer::YES => true,
+            Answer::NO => false,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Ask a yes/no question on the controlling terminal
+///
+/// Used when stdin already delivered the host list and can't answer prompts.
+///
+/// # Arguments
+/// * `prompt` - Question to ask
+///
+/// # Returns
+/// * `bool` - Whether the user agreed
+fn confirm_on_tty(prompt: &str) -> bool {
+    let tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty");
+    let mut tty = match tty {
+        Ok(tty) => tty,
+        Err(_) => {
+            error!("Hosts were read from stdin and there is no terminal to confirm on, use -f");
+            process::exit(1);
+        }
+    };
+    let mut reader = io::BufReader::new(tty.try_clone().unwrap());
+    loop {
+        write!(tty, "{} (y/n) ", prompt).unwrap();
+        let mut answer = String::new();
+        if reader.read_line(&mut answer).unwrap_or(0) == 0 {
+            return false;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return true,
+            "n" | "no" => return false,
+            _ => continue,
+        }
+    }
+}
+
+/// Where the hosts selected with -e and --exclude come from
+enum HostSource {
+    /// Regexes matched against names from known_hosts files
+    KnownHosts(Vec<Host>),
+    /// Regexes matched against Host aliases of an ssh_config file
+    SshConfig(Vec<Host>),
+    /// Group patterns and regexes evaluated against an inventory
+    Inventory(inventory::Inventory),
+    /// Range and list expansion of the expressions themselves
+    Expansion,
+}
+
+impl HostSource {
+    /// Select the hosts matching any of the expressions
+    ///
+    /// # Arguments
+    /// * `expressions` - Expressions given with -e or --exclude
+    ///
+    /// # Returns
+    /// * `Vec<Host>` - Selected hosts, grouped by expression
+    fn select(&self, expressions: &[String]) -> Vec<Host> {
+        match self {
+            HostSource::KnownHosts(hosts) | HostSource::SshConfig(hosts) => {
+                filter_hosts(hosts, expressions)
+            }
+            HostSource::Inventory(inventory) => {
+                let mut all_hosts = Vec::new();
+                for expression in expressions.iter() {
+                    match inventory.select(expression) {
+                        Ok(hosts) => all_hosts.extend(hosts),
+                        Err(e) => {
+                            error!("Error parsing inventory expression '{}'. {}", expression, e);
+                            process::exit(1);
+                        }
+                    }
                 }
-                Some(_) => {
-                    // Same host, continue with current block
+                all_hosts
+            }
+            HostSource::Expansion => {
+                let mut all_hosts = Vec::new();
+                for expression in expressions.iter() {
+                    match expand_string(expression) {
+                        Ok(hosts) => all_hosts.extend(hosts),
+                        Err(e) => {
+                            error!("Error parsing host expression at {}", e);
+                            eprintln!("{}", e.caret(expression));
+                            process::exit(1);
+                        }
+                    }
                 }
+                all_hosts
             }
+        }
+    }
+}
 
-            // Print the log line with colored separator based on stream type
-            let separator = if is_stderr {
-                "│".red()
-            } else {
-                "│".green()
+/// Load the host source chosen on the command line
+///
+/// # Arguments
+/// * `args` - Parsed command-line arguments
+///
+/// # Returns
+/// * `HostSource` - Source to select hosts from
+fn load_host_source(args: &Args) -> HostSource {
+    if args.known_hosts {
+        // Use regex pattern matching against known_hosts file
+        info!("Using known_hosts to build server list.");
+        HostSource::KnownHosts(read_known_hosts(&args.known_hosts_files))
+    } else if args.ssh_config {
+        // Use regex pattern matching against Host aliases of the ssh config
+        let path = args
+            .ssh_config_file
+            .clone()
+            .unwrap_or_else(|| home_dir().join(".ssh").join("config"));
+        info!("Using {} to build server list.", path.display());
+        match ssh_config::read_ssh_config(&path) {
+            Ok(aliases) => HostSource::SshConfig(aliases),
+            Err(e) => {
+                error!("Failed to read {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(path) = &args.inventory {
+        // Use group patterns and regexes against the inventory
+        info!("Using inventory {} to build server list.", path.display());
+        match inventory::Inventory::load(path) {
+            Ok(inventory) => HostSource::Inventory(inventory),
+            Err(e) => {
+                error!("Failed to load inventory {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    } else {
+        // Use pattern expansion syntax (ranges and lists)
+        info!("Using string expansion to build server list.");
+        HostSource::Expansion
+    }
+}
+
+/// Read and parse SSH known_hosts files to extract server names
+///
+/// Without explicit files the user's `~/.ssh/known_hosts` is read together
+/// with the system-wide `/etc/ssh/ssh_known_hosts` when it exists. Any file
+/// that can't be read is a fatal error, except the optional system-wide one.
+///
+/// # Arguments
+/// * `files` - Files given with --known-hosts-file
+///
+/// # Returns
+/// * `Vec<Host>` - Unique hosts found in the known_hosts files
+fn read_known_hosts(files: &[PathBuf]) -> Vec<Host> {
+    let files = if files.is_empty() {
+        let mut defaults = vec![home_dir().join(".ssh").join("known_hosts")];
+        let global = PathBuf::from(GLOBAL_KNOWN_HOSTS);
+        if global.exists() {
+            defaults.push(global);
+        }
+        defaults
+    } else {
+        files.to_vec()
+    };
+
+    let mut hosts: Vec<Host> = Vec::new();
+    for file in &files {
+        info!("Reading {}", file.display());
+        let content = match read_to_string(file) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Failed to read known_hosts file {}: {}", file.display(), e);
+                process::exit(1);
+            }
+        };
+        hosts.extend(known_hosts::parse_known_hosts(&content));
+    }
+    hosts.into_iter().unique().collect()
+}
+
+/// Expand a server pattern string with range and list notation into individual hostnames
+///
+/// Supports two expansion types, which may be nested and combined:
+/// - Range expansion: server-[1:5] → server-1, server-2, server-3, server-4, server-5,
+///   with padding (`[01:12]`), steps (`[0:100:10]`), letters (`[a:f]`) and lists (`[1:3,7]`)
+/// - List expansion: server-{prod,dev} → server-prod, server-dev
+///
+/// # Arguments
+/// * `s` - Pattern string to expand
+///
+/// # Returns
+/// * `Result<Vec<Host>, ParseError>` - List of expanded Host objects, or the syntax error
+fn expand_string(s: &str) -> Result<Vec<Host>, ParseError> {
+    let hostnames = expression::expand(s)?;
+
+    // Convert all expanded strings to Host objects
+    Ok(hostnames
+        .iter()
+        .map(|hostname| parse_host_spec(hostname))
+        .collect())
+}
+
+/// Print a line of command output inside the block of its host
+///
+/// Consecutive lines of the same host share one block; a new block is opened
+/// whenever output from another host arrives in between.
+///
+/// # Arguments
+/// * `display_name` - Shortened hostname used in the output
+/// * `stream` - Stream the line was read from
+/// * `line` - Output line without the trailing newline
+fn print_block_line(display_name: &str, stream: Stream, line: &str) {
+    let mut current_block = CURRENT_BLOCK.lock().unwrap();
+
+    // Check if we need to close the previous block and open a new one
+    match current_block.as_ref() {
+        Some(open_host) if open_host != display_name => {
+            // Close the previous block
+            println!("└ {} ┘", open_host.yellow());
+            // Open new block
+            println!("┌ {} ┐", display_name.yellow());
+            *current_block = Some(display_name.to_string());
+        }
+        None => {
+            // Open new block
+            println!("┌ {} ┐", display_name.yellow());
+            *current_block = Some(display_name.to_string());
+        }
+        Some(_) => {
+            // Same host, continue with current block
+        }
+    }
+
+    // Print the log line with colored separator based on stream type
+    let separator = match stream {
+        Stream::Stdout => "│".green(),
+        Stream::Stderr => "│".red(),
+    };
+    println!(
+        "{} {} {} {}",
+        separator,
+        display_name.yellow(),
+        separator,
+        line
+    );
+}
+
+/// Close the block of a host if it was the last one to print output
+///
+/// # Arguments
+/// * `display_name` - Shortened hostname used in the output
+fn close_block(display_name: &str) {
+    let mut current_block = CURRENT_BLOCK.lock().unwrap();
+    if current_block.as_deref() == Some(display_name) {
+        println!("└ {} ┘", display_name.yellow());
+        *current_block = None;
+    }
+}
+
+/// Format an outcome with color (green for success, red for failure)
+///
+/// # Arguments
+/// * `outcome` - Outcome of the command
+///
+/// # Returns
+/// * `String` - Colored exit code or TIMEOUT marker
+fn format_outcome(outcome: Outcome) -> String {
+    match outcome {
+        Outcome::Exited(0) => format!("{}", "0".green()),
+        Outcome::Exited(code) => format!("{}", code.to_string().red()),
+        Outcome::TimedOut => format!("{}", "TIMEOUT".red()),
+    }
+}
+
+/// Serialize a NDJSON event and print it as a single line
+///
+/// # Arguments
+/// * `event` - JSON object describing the event
+fn emit_event(event: serde_json::Value) {
+    println!("{}", event);
+}
+
+/// What rexec does on every host
+enum Job {
+    /// Run a command, optionally feeding data to its stdin
+    Command {
+        command: String,
+        input: Option<Vec<u8>>,
+        /// Variables of the selected hosts, usable as placeholders
+        variables: HashSet<String>,
+    },
+    /// Copy files to or from the host
+    Transfer(Transfer),
+}
+
+/// Run a job on a single host
+///
+/// Pulls are stored in a subdirectory of the local directory named after the host.
+///
+/// # Arguments
+/// * `transport` - Transport delivering the job to the host
+/// * `host` - Target host, resolved if the transport needs an IP address
+/// * `index` - Position of the host in the list of hosts to run on
+/// * `job` - Command or file copy to run
+/// * `username` - Username filled in for `{user}` when the host has none
+/// * `on_line` - Callback receiving each output line with its stream
+///
+/// # Returns
+/// * `Result<Outcome, HostError>` - Outcome on success or error
+fn run_job(
+    transport: &dyn Transport,
+    host: &Host,
+    index: usize,
+    job: &Job,
+    username: &str,
+    on_line: &(dyn Fn(Stream, String) + Sync),
+) -> Result<Outcome, HostError> {
+    match job {
+        Job::Command {
+            command,
+            input,
+            variables,
+        } => {
+            let command =
+                render_command(command, host, index, username, variables).map_err(|names| {
+                    HostError::Other(format!(
+                        "No value for placeholders {{{}}}",
+                        names.join("}, {")
+                    ))
+                })?;
+            transport.execute(host, &command, input.as_deref(), on_line)
+        }
+        Job::Transfer(Transfer::Pull { remote, local }) => {
+            let dir = local.join(host.name.replace('/', "_"));
+            fs::create_dir_all(&dir).map_err(|e| {
+                HostError::Other(format!("Failed to create {}: {}", dir.display(), e))
+            })?;
+            let transfer = Transfer::Pull {
+                remote: remote.clone(),
+                local: dir,
             };
-            println!(
-                "{} {} {} {}",
-                separator,
-                display_name.yellow(),
-                separator,
-                line
-            );
+            transport.copy(host, &transfer, on_line)
         }
+        Job::Transfer(transfer) => transport.copy(host, transfer, on_line),
+    }
+}
+
+/// Run the command on a single host and render its output in the selected format
+///
+/// # Arguments
+/// * `args` - Parsed command-line arguments
+/// * `transport` - Transport delivering the command to the host
+/// * `host` - Target host, resolved if the transport needs an IP address
+/// * `index` - Position of the host in the list of hosts to run on
+/// * `job` - Command or file copy to run
+/// * `common_suffix` - Optional common suffix for hostname display formatting
+///
+/// # Returns
+/// * `HostReport` - Outcome of the host, with captured output in JSON and collate modes
+fn run_on_host(
+    args: &Args,
+    transport: &dyn Transport,
+    host: &Host,
+    index: usize,
+    job: &Job,
+    common_suffix: &Option<String>,
+) -> HostReport {
+    let hostname = host.name.as_str();
+    let ip = host.ip;
+    let display_name = shorten_hostname(hostname, common_suffix);
+    let captured: Mutex<Vec<(Stream, String)>> = Mutex::new(Vec::new());
+    let collate = args.output == OutputFormat::Text && args.collate;
+    let started = Instant::now();
+
+    if args.output == OutputFormat::Ndjson {
+        emit_event(json!({"event": "host_started", "host": hostname, "ip": ip}));
+    }
+
+    let on_line = |stream: Stream, line: String| match args.output {
+        OutputFormat::Text if collate => captured.lock().unwrap().push((stream, line)),
+        OutputFormat::Text if !args.code => print_block_line(&display_name, stream, &line),
+        OutputFormat::Text => (),
+        OutputFormat::Json => captured.lock().unwrap().push((stream, line)),
+        OutputFormat::Ndjson => emit_event(json!({
+            "event": "line",
+            "host": hostname,
+            "stream": stream,
+            "line": line,
+        })),
     };
 
-    // Capture and display stdout in real-time using a dedicated thread
-    let stdout = child.stdout.take().unwrap();
-    let display_name_stdout = display_name.clone();
-    let code_only_stdout = code_only;
-    let stdout_thread = thread::spawn(move || {
-        let reader = BufReader::new(stdout);
-
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    handle_output(line, &display_name_stdout, code_only_stdout, false);
-                    // false = stdout
+    let files = args
+        .output_dir
+        .as_deref()
+        .map(|dir| HostFiles::create(dir, hostname))
+        .transpose();
+
+    let result = match &files {
+        Ok(files) => {
+            let on_line = |stream: Stream, line: String| {
+                if let Some(files) = files {
+                    files.write_line(stream, &line);
                 }
-                Err(_) => break,
+                on_line(stream, line)
+            };
+            run_job(transport, host, index, job, &local_username(args), &on_line)
+        }
+        Err(e) => Err(HostError::Other(format!(
+            "Failed to create output files: {}",
+            e
+        ))),
+    };
+    let duration = started.elapsed();
+
+    if let Ok(Some(files)) = &files {
+        files.write_outcome(&result);
+    }
+
+    match args.output {
+        OutputFormat::Text => {
+            if !args.code && !collate {
+                close_block(&display_name);
+            }
+            match &result {
+                // For code-only mode, just show hostname and exit code
+                Ok(outcome) if args.code => {
+                    println!("{}: [{}]", display_name.yellow(), format_outcome(*outcome))
+                }
+                Ok(Outcome::TimedOut) => error!(
+                    "{}: [{}]",
+                    display_name.yellow(),
+                    format_outcome(Outcome::TimedOut)
+                ),
+                Ok(_) => (),
+                Err(e) => error!("Error executing command on {}: {}", hostname, e),
             }
         }
+        OutputFormat::Json => (),
+        OutputFormat::Ndjson => {
+            let mut event = outcome_json(&result);
+            event["event"] = json!("host_finished");
+            event["host"] = json!(hostname);
+            event["duration_ms"] = json!(duration.as_millis() as u64);
+            emit_event(event);
+        }
+    }
+
+    HostReport {
+        hostname: hostname.to_string(),
+        ip,
+        result,
+        duration,
+        output: captured.into_inner().unwrap(),
+    }
+}
+
+/// Describe a host result as JSON fields shared by all machine-readable formats
+///
+/// # Arguments
+/// * `result` - Outcome of the command or error message
+///
+/// # Returns
+/// * `serde_json::Value` - Object with `status`, `exit_code` and `error` fields
+fn outcome_json(result: &Result<Outcome, HostError>) -> serde_json::Value {
+    match result {
+        Ok(Outcome::Exited(code)) => json!({
+            "status": if *code == 0 { "ok" } else { "failed" },
+            "exit_code": code,
+            "error": null,
+        }),
+        Ok(Outcome::TimedOut) => json!({"status": "timeout", "exit_code": null, "error": null}),
+        Err(e) => json!({"status": e.kind(), "exit_code": null, "error": e.to_string()}),
+    }
+}
+
+/// Print the final JSON document describing the whole run
+///
+/// # Arguments
+/// * `reports` - Reports of every host that was attempted
+/// * `dns_failed` - Hosts skipped because their name could not be resolved
+/// * `skipped` - Hosts never started because the run was aborted
+/// * `elapsed` - Wall-clock time spent executing commands
+fn print_json_report(
+    reports: &[HostReport],
+    dns_failed: &[String],
+    skipped: &[String],
+    elapsed: Duration,
+) {
+    let hosts: Vec<serde_json::Value> = reports
+        .iter()
+        .map(|report| {
+            let mut host = outcome_json(&report.result);
+            host["host"] = json!(report.hostname);
+            host["ip"] = json!(report.ip);
+            host["duration_ms"] = json!(report.duration.as_millis() as u64);
+            host["stdout"] = json!(report.stream_text(Stream::Stdout));
+            host["stderr"] = json!(report.stream_text(Stream::Stderr));
+            host
+        })
+        .collect();
+
+    let document = json!({
+        "hosts": hosts,
+        "dns_failed": dns_failed,
+        "skipped": skipped,
+        "elapsed_ms": elapsed.as_millis() as u64,
+    });
+    println!("{}", serde_json::to_string_pretty(&document).unwrap());
+}
+
+/// The username given with `-u`, or the current system user
+fn local_username(args: &Args) -> String {
+    args.username.clone().unwrap_or_else(whoami::username)
+}
+
+/// Build the transport selected on the command line
+///
+/// # Arguments
+/// * `args` - Parsed command-line arguments
+///
+/// # Returns
+/// * `Box<dyn Transport>` - Transport used for every target
+fn make_transport(args: &Args) -> Box<dyn Transport> {
+    let timeout = args.timeout.map(Duration::from_secs);
+
+    // Options that only make sense for the ssh binary
+    if args.transport == TransportKind::Native
+        && (!args.ssh_options.is_empty()
+            || args.jump_host.is_some()
+            || args.ssh_config_file.is_some())
+    {
+        error!("--ssh-option, --jump-host and --ssh-config-file are only supported by the ssh transport");
+        process::exit(1);
+    }
+
+    // scp over libssh2 moves single files, refuse directories before touching any host
+    if let (TransportKind::Native, Some(Transfer::Push { local, .. })) =
+        (args.transport, &args.push)
+    {
+        if local.is_dir() {
+            error!(
+                "{} is a directory, the native transport only copies single files",
+                local.display()
+            );
+            process::exit(1);
+        }
+    }
+
+    match args.transport {
+        TransportKind::Ssh => Box::new(SshTransport {
+            username: args.username.clone(),
+            timeout,
+            connect_timeout: args.connect_timeout,
+            host_key_checking: args.host_key_checking,
+            port: args.port,
+            identity: args.identity.clone(),
+            jump_host: args.jump_host.clone(),
+            options: args.ssh_options.clone(),
+            config_file: args.ssh_config_file.clone(),
+        }),
+        #[cfg(feature = "native-ssh")]
+        TransportKind::Native => Box::new(native::NativeTransport {
+            username: local_username(args),
+            timeout,
+            connect_timeout: args.connect_timeout,
+            host_key_checking: args.host_key_checking,
+            port: args.port,
+            identity: args.identity.clone(),
+        }),
+        #[cfg(not(feature = "native-ssh"))]
+        TransportKind::Native => {
+            error!("Native SSH transport is not available: rexec was built without the 'native-ssh' feature");
+            process::exit(1);
+        }
+        TransportKind::Local => Box::new(LocalTransport { timeout }),
+        TransportKind::Docker => Box::new(DockerTransport { timeout }),
+        TransportKind::Kubectl => Box::new(KubectlTransport { timeout }),
+    }
+}
+
+/// Run a job for every item using a bounded pool of worker threads
+///
+/// Each worker pulls the next item as soon as it finishes the previous one,
+/// so a slow item only occupies its own slot instead of stalling a whole batch.
+///
+/// # Arguments
+/// * `items` - Items to process, picked up in order
+/// * `parallel` - Maximum number of jobs running at the same time
+/// * `job` - Function executed for each item
+///
+/// # Returns
+/// * `Vec<R>` - Job results in the same order as `items`
+fn run_parallel<T, R, F>(items: &[T], parallel: usize, job: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let workers = parallel.clamp(1, items.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                // Claim the next unprocessed item, stop when the queue is drained
+                let idx = next.fetch_add(1, Ordering::SeqCst);
+                if idx >= items.len() {
+                    break;
+                }
+                let result = job(&items[idx]);
+                results.lock().unwrap()[idx] = Some(result);
+            });
+        }
     });
 
-    // Capture and display stderr in real-time using a dedicated thread
-    let stderr = child.stderr.take().unwrap();
-    let display_name_stderr = display_name.clone();
-    let code_only_stderr = code_only;
-    let stderr_thread = thread::spawn(move || {
-        let reader = BufReader::new(stderr);
-
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    handle_output(line, &display_name_stderr, code_only_stderr, true);
-                    // true = stderr
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item is processed by a worker"))
+        .collect()
+}
+
+/// Compress hostnames into the range syntax understood by `expand_string`
+///
+/// Hostnames differing only in their last number are merged, so `web-1`,
+/// `web-2`, `web-3` and `web-7` become `web-[1:3,7]`. Zero-padded numbers are
+/// only merged with numbers of the same width, so `node-[01:12]` round-trips.
+///
+/// # Arguments
+/// * `hostnames` - Hostnames to compress
+///
+/// # Returns
+/// * `Vec<String>` - Compressed host expressions in order of first appearance
+fn compress_hostnames(hostnames: &[String]) -> Vec<String> {
+    lazy_static! {
+        static ref LAST_NUMBER: Regex = Regex::new(r"^(.*?)(\d+)(\D*)$").unwrap();
+    }
+
+    // Widths of zero-padded numbers seen for every prefix/suffix pair
+    let captures: Vec<_> = hostnames
+        .iter()
+        .map(|hostname| LAST_NUMBER.captures(hostname).filter(|c| c[2].len() < 18))
+        .collect();
+    let mut padded_widths: Vec<((String, String), usize)> = Vec::new();
+    for c in captures.iter().flatten() {
+        if c[2].len() > 1 && c[2].starts_with('0') {
+            padded_widths.push(((c[1].to_string(), c[3].to_string()), c[2].len()));
+        }
+    }
+
+    // Collect numbers for every prefix/suffix/width, preserving first appearance order
+    let mut groups: Vec<((String, String, usize), Vec<i64>)> = Vec::new();
+    let mut result: Vec<Option<String>> = Vec::new();
+    for (hostname, captures) in hostnames.iter().zip(captures) {
+        let key = captures.and_then(|c| {
+            let digits = &c[2];
+            let affix = (c[1].to_string(), c[3].to_string());
+            let width = if digits.len() > 1 && digits.starts_with('0') {
+                digits.len()
+            } else if padded_widths.contains(&(affix.clone(), digits.len())) {
+                // e.g. `10` next to `09` belongs to the padded range
+                digits.len()
+            } else {
+                0
+            };
+            // Padded numbers of another width can't share a range
+            let ambiguous = width > 0
+                && padded_widths
+                    .iter()
+                    .any(|(a, w)| *a == affix && *w != width);
+            (!ambiguous).then(|| ((affix.0, affix.1, width), digits.parse::<i64>().unwrap()))
+        });
+        match key {
+            Some((key, number)) => match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, numbers)) => numbers.push(number),
+                None => {
+                    groups.push((key, vec![number]));
+                    result.push(None);
+                }
+            },
+            None => result.push(Some(hostname.clone())),
+        }
+    }
+
+    // Turn every group into one range of consecutive runs
+    let mut compressed = groups
+        .into_iter()
+        .map(|((prefix, suffix, width), mut numbers)| {
+            numbers.sort_unstable();
+            numbers.dedup();
+            let number = |n: i64| format!("{:0width$}", n, width = width);
+            let mut parts: Vec<String> = Vec::new();
+            let mut start = 0;
+            for i in 0..numbers.len() {
+                if i + 1 == numbers.len() || numbers[i + 1] != numbers[i] + 1 {
+                    if start == i {
+                        parts.push(number(numbers[i]));
+                    } else {
+                        parts.push(format!("{}:{}", number(numbers[start]), number(numbers[i])));
+                    }
+                    start = i + 1;
                 }
-                Err(_) => break,
+            }
+            if numbers.len() == 1 {
+                format!("{}{}{}", prefix, parts[0], suffix)
+            } else {
+                format!("{}[{}]{}", prefix, parts.join(","), suffix)
+            }
+        });
+
+    result
+        .into_iter()
+        .map(|name| name.unwrap_or_else(|| compressed.next().unwrap()))
+        .collect()
+}
+
+/// Print every distinct output once together with the hosts that produced it
+///
+/// # Arguments
+/// * `reports` - Reports of every host that was attempted
+/// * `common_suffix` - Optional common suffix for hostname display formatting
+fn print_collated(reports: &[HostReport], common_suffix: &Option<String>) {
+    // Group hosts by their output and outcome, in order of first appearance
+    let mut groups: Vec<(&HostReport, Vec<String>)> = Vec::new();
+    let mut index: HashMap<_, usize> = HashMap::new();
+    for report in reports {
+        let outcome = match &report.result {
+            Ok(outcome) => *outcome,
+            Err(_) => continue,
+        };
+        let display_name = shorten_hostname(&report.hostname, common_suffix);
+        match index.get(&(outcome, &report.output)) {
+            Some(&i) => groups[i].1.push(display_name),
+            None => {
+                index.insert((outcome, &report.output), groups.len());
+                groups.push((report, vec![display_name]));
             }
         }
-    });
+    }
 
-    // Wait for command to complete
-    let status = match child.wait() {
-        Ok(status) => status,
-        Err(e) => return Err(format!("Failed to wait for SSH process: {}", e)),
-    };
+    for (report, hostnames) in groups {
+        let title = compress_hostnames(&hostnames).join(" ");
+        let code = format_outcome(*report.result.as_ref().unwrap());
+        println!(
+            "┌ {} ({} host{}) [{}] ┐",
+            title.yellow(),
+            hostnames.len(),
+            if hostnames.len() == 1 { "" } else { "s" },
+            code
+        );
+        for (stream, line) in &report.output {
+            let separator = match stream {
+                Stream::Stdout => "│".green(),
+                Stream::Stderr => "│".red(),
+            };
+            println!("{} {}", separator, line);
+        }
+        println!("└ {} ┘", title.yellow());
+    }
+}
+
+/// Print an overview of the whole run once every host has finished
+///
+/// # Arguments
+/// * `reports` - Reports of every host that was attempted
+/// * `dns_failed` - Hosts skipped because their name could not be resolved
+/// * `skipped` - Hosts never started because the run was aborted
+/// * `elapsed` - Wall-clock time spent executing commands
+fn print_summary(
+    reports: &[HostReport],
+    dns_failed: &[String],
+    skipped: &[String],
+    elapsed: Duration,
+) {
+    let plural = |n: usize| if n == 1 { "host" } else { "hosts" };
+
+    // Count hosts per exit code, keeping timeouts in their own bucket
+    let mut exit_codes: BTreeMap<i32, usize> = BTreeMap::new();
+    let mut timed_out: Vec<&str> = Vec::new();
+    let mut failed: Vec<&str> = Vec::new();
+    let mut errored: Vec<(&str, &HostError)> = Vec::new();
+    for report in reports {
+        let hostname = report.hostname.as_str();
+        match &report.result {
+            Ok(Outcome::Exited(code)) => {
+                *exit_codes.entry(*code).or_default() += 1;
+                if *code != 0 {
+                    failed.push(hostname);
+                }
+            }
+            Ok(Outcome::TimedOut) => timed_out.push(hostname),
+            Err(e) => errored.push((hostname, e)),
+        }
+    }
 
-    // Wait for stdout and stderr threads to complete
-    stdout_thread.join().unwrap();
-    stderr_thread.join().unwrap();
+    // Count errors per category (connection, authentication, host key, ...)
+    let mut error_kinds: BTreeMap<&str, usize> = BTreeMap::new();
+    for (_, e) in &errored {
+        *error_kinds.entry(e.kind()).or_default() += 1;
+    }
+
+    eprintln!();
+    info!(
+        "Summary: {} {} in {:.1}s",
+        reports.len(),
+        plural(reports.len()),
+        elapsed.as_secs_f64()
+    );
+    for (code, count) in &exit_codes {
+        if *code == 0 {
+            info!(
+                "  exit code {}: {} {}",
+                code.to_string().green(),
+                count,
+                plural(*count)
+            );
+        } else {
+            warn!(
+                "  exit code {}: {} {}",
+                code.to_string().red(),
+                count,
+                plural(*count)
+            );
+        }
+    }
+    if !timed_out.is_empty() {
+        warn!(
+            "  {}: {} {}",
+            "TIMEOUT".red(),
+            timed_out.len(),
+            plural(timed_out.len())
+        );
+    }
+    for (kind, count) in &error_kinds {
+        warn!("  {}: {} {}", kind.red(), count, plural(*count));
+    }
+    if !failed.is_empty() {
+        error!("Failed: {}", failed.join(" "));
+    }
+    if !timed_out.is_empty() {
+        error!("Timed out: {}", timed_out.join(" "));
+    }
+    for (hostname, e) in &errored {
+        error!("Not run on {}: {}", hostname, e);
+    }
+    if !dns_failed.is_empty() {
+        error!("DNS resolve failed: {}", dns_failed.join(" "));
+    }
+    if !skipped.is_empty() {
+        error!("Skipped after abort: {}", skipped.join(" "));
+    }
+}
 
-    // Close the block if this host was the last one to output
-    if !code_only {
-        let mut current_block = CURRENT_BLOCK.lock().unwrap();
-        if let Some(open_host) = current_block.as_ref() {
-            if open_host == &display_name {
-                println!("└ {} ┘", display_name.yellow());
-                *current_block = None;
+/// Resolve hostnames via DNS in parallel and report the result of every lookup
+///
+/// Hosts the transport reaches without a local address, such as containers or
+/// hosts behind a jump host, are kept as they are.
+///
+/// # Arguments
+/// * `matched_hosts` - Hosts to resolve, in display order
+/// * `transport` - Transport deciding which hosts need an address
+///
+/// # Returns
+/// * `(Vec<Host>, Vec<String>)` - Usable hosts, and the hostnames that failed to resolve
+fn resolve_hosts(matched_hosts: &[Host], transport: &dyn Transport) -> (Vec<Host>, Vec<String>) {
+    // Perform DNS resolution for all hosts in parallel, collect keeps the original order
+    let lookups: Vec<Option<Option<IpAddr>>> = matched_hosts
+        .par_iter()
+        .map(|host| {
+            transport.resolves(host).then(|| {
+                lookup_host(host.address.as_ref().unwrap_or(&host.name))
+                    .ok()
+                    .and_then(|ips| ips.first().copied())
+            })
+        })
+        .collect();
+
+    // Display all matched hosts with their resolved IPs
+    let mut valid_hosts = Vec::new();
+    let mut dns_failed = Vec::new();
+    for (host, lookup) in matched_hosts.iter().zip(lookups) {
+        match lookup {
+            None => {
+                info!("{}", host.name);
+                valid_hosts.push(host.clone());
+            }
+            Some(Some(ip)) => {
+                info!("{} [{}]", host.name, ip);
+                valid_hosts.push(Host {
+                    ip: Some(ip),
+                    ..host.clone()
+                });
+            }
+            Some(None) => {
+                error!("DNS resolve failed: {}", host.name.red());
+                dns_failed.push(host.name.clone());
             }
         }
     }
+    (valid_hosts, dns_failed)
+}
 
-    // Format exit code with color (green for success, red for failure)
-    let exit_code = status.code().unwrap_or(-1);
-    let code_string = if exit_code == 0 {
-        format!("{}", exit_code.to_string().green())
-    } else {
-        format!("{}", exit_code.to_string().red())
-    };
+/// Print the selected hosts for --list
+///
+/// # Arguments
+/// * `hosts` - Selected hosts, resolved if --resolve was given
+/// * `format` - Output format of the list
+fn print_host_list(hosts: &[Host], format: ListFormat) {
+    match format {
+        ListFormat::Plain => {
+            for host in hosts {
+                match host.ip {
+                    Some(ip) => println!("{}\t{}", host.name, ip),
+                    None => println!("{}", host.name),
+                }
+            }
+        }
+        ListFormat::Json => {
+            let list: Vec<serde_json::Value> = hosts
+                .iter()
+                .map(|host| {
+                    json!({
+                        "host": host.name,
+                        "address": host.address,
+                        "ip": host.ip,
+                        "user": host.user,
+                        "port": host.port,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::Value::Array(list));
+        }
+        ListFormat::Ranges => {
+            let hostnames: Vec<String> = hosts.iter().map(|host| host.name.clone()).collect();
+            for expression in compress_hostnames(&hostnames) {
+                println!("{}", expression);
+            }
+        }
+    }
+}
 
-    // For code-only mode, just show hostname and exit code
-    if code_only {
-        println!("{}: [{}]", display_name.yellow(), code_string);
+/// Split the hosts into the canary and --serial batches
+///
+/// # Arguments
+/// * `total` - Number of hosts to run on
+/// * `canary` - Number of hosts run alone first
+/// * `serial` - Size of the batches after the canary
+///
+/// # Returns
+/// * `Vec<Range<usize>>` - Index ranges of the batches, in order
+fn plan_batches(total: usize, canary: Option<usize>, serial: Option<Amount>) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    if let Some(canary) = canary {
+        let end = canary.min(total);
+        batches.push(0..end);
+        start = end;
+    }
+    let size = serial.map_or(total, |serial| serial.of(total)).max(1);
+    while start < total {
+        let end = (start + size).min(total);
+        batches.push(start..end);
+        start = end;
+    }
+    batches
+}
+
+/// Compute rexec's exit status from the outcome of every host
+///
+/// Connection problems (DNS failures, hosts the transport couldn't reach or
+/// start the command on) take precedence over remote command failures.
+///
+/// # Arguments
+/// * `reports` - Reports of every host that was attempted
+/// * `dns_failed` - Hosts skipped because their name could not be resolved
+/// * `fail_on` - Policy deciding when failures make rexec fail
+///
+/// # Returns
+/// * `i32` - Process exit status
+fn exit_status(reports: &[HostReport], dns_failed: &[String], fail_on: FailOn) -> i32 {
+    let mut unreachable = dns_failed.len();
+    let mut command_failed = 0;
+    for report in reports {
+        match report.result {
+            Ok(Outcome::Exited(0)) => (),
+            Err(_) => unreachable += 1,
+            Ok(_) => command_failed += 1,
+        }
     }
 
-    Ok(exit_code)
+    let failed = unreachable + command_failed;
+    let total = reports.len() + dns_failed.len();
+    let should_fail = match fail_on {
+        FailOn::Any => failed > 0,
+        FailOn::All => failed == total,
+        FailOn::None => false,
+    };
+
+    if !should_fail {
+        0
+    } else if unreachable > 0 {
+        EXIT_UNREACHABLE
+    } else {
+        EXIT_COMMAND_FAILED
+    }
 }
 
 /// Main entry point for the application
@@ -435,44 +2086,78 @@ fn main() {
         .format_target(false)
         .init();
 
+    // Children in their own process group don't see Ctrl-C from the terminal
+    transport::forward_interrupts();
+
     // Parse command-line arguments
     let args = Args::parse();
 
+    // stdin can only deliver one of the host list, the script and the command input
+    if args.expression.iter().any(|e| e == "-")
+        && (args.script.as_deref() == Some(Path::new("-")) || args.stdin)
+    {
+        error!("-e - can't be combined with --script - or --stdin, they all read from stdin");
+        process::exit(1);
+    }
+
+    let transport = make_transport(&args);
+
     // Build the list of target hosts based on user selection method
-    let hosts = if args.known_hosts {
-        // Use regex pattern matching against known_hosts file
-        info!("Using ~/.ssh/known_hosts to build server list.");
-        let known_hosts = read_known_hosts();
-        let mut all_hosts = Vec::new();
-        for expression in args.expression.iter() {
-            let re = match Regex::new(expression) {
-                Ok(result) => result,
-                Err(e) => {
-                    error!("Error parsing regex. {}", e);
-                    process::exit(1);
-                }
-            };
-            let matched: Vec<Host> = known_hosts
-                .clone()
-                .into_iter()
-                .filter(|r| re.is_match(&r.name.clone()))
-                .collect();
-            all_hosts.extend(matched);
-        }
-        all_hosts
-    } else {
-        // Use pattern expansion syntax (ranges and lists)
-        info!("Using string expansion to build server list.");
-        let mut all_hosts = Vec::new();
-        for expression in args.expression.iter() {
-            all_hosts.extend(expand_string(expression));
-        }
-        all_hosts
-    };
+    let source = load_host_source(&args);
+    let expressions = collect_expressions(&args);
+    let hosts = source.select(&expressions);
+
+    // Remove excluded hosts before anything is resolved
+    let excluded: Vec<String> = source
+        .select(&args.exclude)
+        .into_iter()
+        .map(|host| host.name)
+        .collect();
+    let (excluded, hosts): (Vec<Host>, Vec<Host>) = hosts
+        .into_iter()
+        .partition(|host| excluded.contains(&host.name));
+    if !excluded.is_empty() {
+        info!(
+            "Excluded: {}",
+            excluded.iter().map(|host| &host.name).unique().join(", ")
+        );
+    }
 
     // Remove duplicate hosts while preserving original order
     let matched_hosts: Vec<_> = hosts.into_iter().unique().collect();
 
+    // Only show the selection in --list mode
+    if let Some(format) = args.list {
+        let (listed_hosts, dns_failed) = if args.resolve {
+            info!("Matched hosts:");
+            resolve_hosts(&matched_hosts, transport.as_ref())
+        } else {
+            (matched_hosts, Vec::new())
+        };
+        print_host_list(&listed_hosts, format);
+        process::exit(if dns_failed.is_empty() {
+            0
+        } else {
+            EXIT_UNREACHABLE
+        });
+    }
+    // Copy files, run --command directly or stream --script into the interpreter
+    let mut job = match (&args.push, &args.pull, &args.script) {
+        (Some(push), _, _) => Job::Transfer(push.clone()),
+        (_, Some(pull), _) => Job::Transfer(pull.clone()),
+        (_, _, Some(path)) => Job::Command {
+            command: script_command(&args.interpreter, &args.script_args),
+            input: Some(read_script(path)),
+            variables: HashSet::new(),
+        },
+        // clap only lets --command be omitted together with --list, --script, --push or --pull
+        _ => Job::Command {
+            command: args.command.clone().unwrap(),
+            input: args.stdin.then(read_stdin),
+            variables: HashSet::new(),
+        },
+    };
+
     // Log parallelism setting if not using the default
     if args.parallel != 100 {
         warn!("Parallelism: {} thread{}", &args.parallel, {
@@ -484,74 +2169,63 @@ fn main() {
         });
     }
 
-    // Store hosts with their original indices to preserve ordering
-    let mut host_with_indices: Vec<(Host, usize)> = Vec::new();
-    for (idx, host) in matched_hosts.iter().enumerate() {
-        host_with_indices.push((host.clone(), idx));
-    }
-
     info!("Matched hosts:");
 
-    // Perform DNS resolution for all hosts in parallel
-    // Results are stored with original indices to maintain order
-    let resolved_ips_with_indices = Arc::new(Mutex::new(Vec::<(String, IpAddr, usize)>::new()));
+    // Containers and local targets are used as they are, network hosts are resolved first
+    let (valid_hosts, dns_failed) = resolve_hosts(&matched_hosts, transport.as_ref());
 
-    host_with_indices
-        .par_iter()
-        .for_each(|(host, idx)| match lookup_host(&host.name) {
-            Ok(ips) if !ips.is_empty() => {
-                let ip = ips[0];
-                let mut results = resolved_ips_with_indices.lock().unwrap();
-                results.push((host.name.clone(), ip, *idx));
-            }
-            Ok(_) => {
-                let mut results = resolved_ips_with_indices.lock().unwrap();
-                results.push((
-                    host.name.clone(),
-                    IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
-                    *idx,
-                ));
-            }
-            Err(_) => {
-                let mut results = resolved_ips_with_indices.lock().unwrap();
-                results.push((
-                    host.name.clone(),
-                    IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
-                    *idx,
-                ));
-            }
-        });
-
-    // Sort hosts by original index to maintain consistent display order
-    let mut resolved_hosts = resolved_ips_with_indices.lock().unwrap().clone();
-    resolved_hosts.sort_by_key(|(_, _, idx)| *idx);
+    // Exit if no valid hosts remain
+    if valid_hosts.is_empty() {
+        error!("No valid hosts to connect to");
+        process::exit(EXIT_UNREACHABLE);
+    }
 
-    // Display all matched hosts with their resolved IPs
-    for (hostname, ip, _) in &resolved_hosts {
-        if ip.is_unspecified() {
-            error!("DNS resolve failed: {}", hostname.red());
-        } else {
-            info!("{} [{}]", hostname, ip);
+    // Refuse to run anywhere if a placeholder can't be filled in for some host
+    if let Job::Command {
+        command, variables, ..
+    } = &mut job
+    {
+        // Only these and the built-in names are placeholders, other braces belong to the command
+        *variables = valid_hosts
+            .iter()
+            .flat_map(|host| host.vars.keys().cloned())
+            .collect();
+        let username = local_username(&args);
+        let mut missing: Vec<(String, Vec<&str>)> = Vec::new();
+        for (index, host) in valid_hosts.iter().enumerate() {
+            for name in render_command(command, host, index, &username, variables)
+                .err()
+                .unwrap_or_default()
+            {
+                match missing.iter_mut().find(|(missing, _)| *missing == name) {
+                    Some((_, hosts)) => hosts.push(&host.name),
+                    None => missing.push((name, vec![&host.name])),
+                }
+            }
+        }
+        if !missing.is_empty() {
+            for (name, hosts) in &missing {
+                error!(
+                    "Placeholder {{{}}} has no value for {}",
+                    name,
+                    hosts.join(" ")
+                );
+            }
+            error!("Write {{{{name}}}} for a literal {{name}}");
+            process::exit(1);
         }
     }
 
-    // Filter out hosts that couldn't be resolved
-    let valid_hosts: Vec<(String, IpAddr, usize)> = resolved_hosts
-        .into_iter()
-        .filter(|(_, ip, _)| !ip.is_unspecified())
-        .collect();
-
-    // Exit if no valid hosts remain
-    if valid_hosts.is_empty() {
-        error!("No valid hosts to connect to");
-        process::exit(1);
+    // Prepare the directory for per-host output files
+    if let Some(dir) = &args.output_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Failed to create output directory {}: {}", dir.display(), e);
+            process::exit(1);
+        }
     }
 
     // Find common domain suffix to optimize display
-    let hostnames: Vec<String> = valid_hosts
-        .iter()
-        .map(|(hostname, _, _)| hostname.clone())
-        .collect();
+    let hostnames: Vec<String> = valid_hosts.iter().map(|host| host.name.clone()).collect();
     let common_suffix = find_common_suffix(&hostnames);
 
     // Inform user about display optimization if common suffix found
@@ -563,64 +2237,162 @@ fn main() {
     }
 
     // Ask for confirmation before proceeding (unless --noconfirm is specified)
-    if !args.noconfirm
-        && match Question::new(&*format!(
-            "Continue on following {} servers?",
-            &valid_hosts.len()
-        ))
-        .confirm()
-        {
-            Answer::YES => true,
-            Answer::NO => {
-                warn!("Stopped");
-                process::exit(0);
-            }
-            _ => unreachable!(),
+    if !args.noconfirm {
+        let prompt = format!("Continue on following {} servers?", &valid_hosts.len());
+        if !confirm(&args, &prompt) {
+            warn!("Stopped");
+            process::exit(0);
         }
-    {
         info!("Run command on {} servers.", &valid_hosts.len());
     }
 
-    // Execute commands using system SSH client
-    let batch_size = args.parallel as usize;
-    let mut processed = 0;
-
-    while processed < valid_hosts.len() {
-        let end = std::cmp::min(processed + batch_size, valid_hosts.len());
-        let batch = &valid_hosts[processed..end];
-
-        // Create a thread for each host in the current batch
-        let mut handles = Vec::new();
-
-        for (hostname, _, _) in batch {
-            let hostname = hostname.clone();
-            let username = args.username.clone();
-            let command = args.command.clone();
-            let common_suffix_clone = common_suffix.clone();
-            let code_only = args.code;
-
-            // Execute SSH command in a separate thread
-            let handle = thread::spawn(move || {
-                match execute_ssh_command(
-                    &hostname,
-                    &username,
-                    &command,
-                    &common_suffix_clone,
-                    code_only,
-                ) {
-                    Ok(_) => (),
-                    Err(e) => error!("Error executing command on {}: {}", hostname, e),
-                }
-            });
+    // Run the canary first, then the remaining hosts in --serial batches
+    let started = Instant::now();
+    let indexed_hosts: Vec<(usize, &Host)> = valid_hosts.iter().enumerate().collect();
+    let batches = plan_batches(
+        valid_hosts.len(),
+        args.canary.map(|canary| canary as usize),
+        args.serial,
+    );
+    let total = valid_hosts.len();
+    let failures = AtomicUsize::new(0);
+    let threshold_exceeded = |failures: usize| {
+        args.max_fail
+            .is_some_and(|max_fail| max_fail.exceeded_by(failures, total))
+    };
+    let mut reports: Vec<HostReport> = Vec::new();
+    let mut skipped: Vec<String> = Vec::new();
+    let mut aborted = false;
+    for (number, batch) in batches.iter().enumerate() {
+        let batch_hosts = &indexed_hosts[batch.clone()];
+        if aborted {
+            skipped.extend(batch_hosts.iter().map(|(_, host)| host.name.clone()));
+            continue;
+        }
+        let is_canary = number == 0 && args.canary.is_some();
+        if is_canary {
+            info!("Canary: {} of {} servers", batch_hosts.len(), total);
+        } else if batches.len() > 1 {
+            info!(
+                "Batch {}/{}: {} servers",
+                number + 1,
+                batches.len(),
+                batch_hosts.len()
+            );
+        }
 
-            handles.push(handle);
+        // Execute commands using system SSH client, keeping up to `parallel` sessions in flight
+        let results = run_parallel(batch_hosts, args.parallel as usize, |(index, host)| {
+            // Don't start new hosts once too many have failed
+            if threshold_exceeded(failures.load(Ordering::SeqCst)) {
+                return None;
+            }
+            let report = run_on_host(
+                &args,
+                transport.as_ref(),
+                host,
+                *index,
+                &job,
+                &common_suffix,
+            );
+            if report.result != Ok(Outcome::Exited(0)) {
+                failures.fetch_add(1, Ordering::SeqCst);
+            }
+            Some(report)
+        });
+        for (result, (_, host)) in results.into_iter().zip(batch_hosts) {
+            match result {
+                Some(report) => reports.push(report),
+                None => skipped.push(host.name.clone()),
+            }
         }
 
-        // Wait for all threads in this batch to complete
-        for handle in handles {
-            handle.join().unwrap();
+        let failed = failures.load(Ordering::SeqCst);
+        let remaining = total - batch.end;
+        if threshold_exceeded(failed) {
+            error!(
+                "{} of {} servers failed, exceeding --max-fail. Aborting.",
+                failed, total
+            );
+            aborted = true;
+        } else if is_canary && remaining > 0 {
+            if failed > 0 {
+                error!("Canary failed on {} servers. Aborting.", failed);
+                aborted = true;
+            } else if !args.noconfirm {
+                let prompt = format!(
+                    "Canary succeeded. Continue on remaining {} servers?",
+                    remaining
+                );
+                if !confirm(&args, &prompt) {
+                    warn!("Stopped after canary");
+                    aborted = true;
+                }
+            }
         }
+    }
+
+    match args.output {
+        OutputFormat::Json => print_json_report(&reports, &dns_failed, &skipped, started.elapsed()),
+        // NDJSON already streamed every line, collated blocks would corrupt it
+        OutputFormat::Text if args.collate => print_collated(&reports, &common_suffix),
+        _ => (),
+    }
+    print_summary(&reports, &dns_failed, &skipped, started.elapsed());
+    process::exit(exit_status(&reports, &dns_failed, args.fail_on));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render a command for a host with an `env` variable
+    fn render(template: &str) -> Result<String, Vec<String>> {
+        let host = Host {
+            name: "web-1.example.com".to_string(),
+            ip: Some("10.0.0.1".parse().unwrap()),
+            port: Some(2222),
+            vars: BTreeMap::from([("env".to_string(), "prod eu".to_string())]),
+            ..Default::default()
+        };
+        let variables = HashSet::from(["env".to_string(), "rack".to_string()]);
+        render_command(template, &host, 0, "deploy", &variables)
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        assert_eq!(
+            render("echo {host} {short} {ip} {index} {user} {port}").unwrap(),
+            "echo web-1.example.com web-1 10.0.0.1 1 deploy 2222"
+        );
+    }
+
+    #[test]
+    fn values_are_quoted_unless_raw() {
+        assert_eq!(render("echo {env}").unwrap(), "echo 'prod eu'");
+        assert_eq!(render("echo {env:raw}").unwrap(), "echo prod eu");
+    }
+
+    #[test]
+    fn other_braces_are_left_alone() {
+        assert_eq!(render("echo {{host}}").unwrap(), "echo {host}");
+        assert_eq!(
+            render("echo ${HOME} ${env}").unwrap(),
+            "echo ${HOME} ${env}"
+        );
+        assert_eq!(
+            render("awk 'BEGIN{exit}' /etc/hosts").unwrap(),
+            "awk 'BEGIN{exit}' /etc/hosts"
+        );
+        assert_eq!(
+            render("sed -n '/h/{p}' /etc/hosts").unwrap(),
+            "sed -n '/h/{p}' /etc/hosts"
+        );
+        assert_eq!(render("echo {a,b} {1..3}").unwrap(), "echo {a,b} {1..3}");
+    }
 
-        processed = end;
+    #[test]
+    fn missing_values_are_reported() {
+        assert_eq!(render("echo {rack} {rack:raw}").unwrap_err(), ["rack"]);
     }
 }
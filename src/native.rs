@@ -0,0 +1,635 @@
+//! In-process SSH transport built on libssh2
+//!
+//! Used instead of spawning the system `ssh` binary when rexec is built with
+//! the `native-ssh` feature and run with `--transport native`.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHostKeyFormat, Session};
+
+use crate::transport::{Transfer, Transport};
+use crate::{Host, HostError, HostKeyChecking, Outcome, Stream};
+
+/// SSH port used when neither the host nor --port specify one
+const SSH_PORT: u16 = 22;
+
+/// Connection timeout used when `--connect-timeout` is not given
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Private keys tried in order when the SSH agent can't authenticate
+const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Serializes updates of known_hosts between concurrent sessions
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Buffers partial output of one stream and hands out complete lines
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Append freshly read bytes and pass every complete line to `on_line`
+    fn push(&mut self, data: &[u8], stream: Stream, on_line: &(dyn Fn(Stream, String) + Sync)) {
+        self.pending.extend_from_slice(data);
+        while let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            on_line(stream, String::from_utf8_lossy(&line[..pos]).into_owned());
+        }
+    }
+
+    /// Flush a trailing line that was not terminated by a newline
+    fn finish(&mut self, stream: Stream, on_line: &(dyn Fn(Stream, String) + Sync)) {
+        if !self.pending.is_empty() {
+            on_line(stream, String::from_utf8_lossy(&self.pending).into_owned());
+            self.pending.clear();
+        }
+    }
+}
+
+/// Location of the user's SSH directory
+fn ssh_dir() -> PathBuf {
+    crate::home_dir().join(".ssh")
+}
+
+/// Check the host key of a fresh session against the user's known_hosts
+///
+/// # Arguments
+/// * `session` - Session that completed the handshake
+/// * `hostname` - Target server hostname, used for the host key lookup
+/// * `port` - Port the session is connected to
+/// * `host_key_checking` - Policy for unknown and changed host keys
+///
+/// # Returns
+/// * `Result<(), HostError>` - Ok if the key is acceptable under the policy
+fn verify_host_key(
+    session: &Session,
+    hostname: &str,
+    port: u16,
+    host_key_checking: HostKeyChecking,
+) -> Result<(), HostError> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| HostError::HostKey("server sent no host key".to_string()))?;
+    let known_hosts_file = ssh_dir().join("known_hosts");
+
+    // Hold the lock across reading and writing so concurrent sessions don't lose entries
+    let _guard = KNOWN_HOSTS_LOCK.lock().unwrap();
+    let mut known_hosts = session
+        .known_hosts()
+        .map_err(|e| HostError::Other(e.to_string()))?;
+    if known_hosts_file.exists() {
+        known_hosts
+            .read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)
+            .map_err(|e| HostError::Other(format!("Failed to read known_hosts: {}", e)))?;
+    }
+
+    let check = known_hosts.check_port(hostname, port, key);
+    if judge_host_key(check, hostname, &known_hosts_file, host_key_checking)? {
+        let entry = match port {
+            SSH_PORT => hostname.to_string(),
+            port => format!("[{}]:{}", hostname, port),
+        };
+        append_known_host(session, &known_hosts_file, &entry, key, key_type)
+            .map_err(|e| HostError::Other(format!("Failed to update known_hosts: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// Append a single host key line to a known_hosts file
+///
+/// libssh2 can only write back the entries it parsed, which would drop comments
+/// and rewrite the user's file, so the new line is rendered on its own and appended.
+///
+/// # Arguments
+/// * `session` - Session whose host key is recorded
+/// * `file` - known_hosts file to append to
+/// * `entry` - Host pattern of the line, `name` or `[name]:port`
+/// * `key` - Raw host key
+/// * `key_type` - Type of the host key
+///
+/// # Returns
+/// * `io::Result<()>` - Ok once the line is written
+fn append_known_host(
+    session: &Session,
+    file: &Path,
+    entry: &str,
+    key: &[u8],
+    key_type: HostKeyType,
+) -> io::Result<()> {
+    // A fresh collection holds nothing but the new entry
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.add(entry, key, "", KnownHostKeyFormat::from(key_type))?;
+    let mut line = String::new();
+    for host in known_hosts.iter()? {
+        // libssh2 leaves a space for the empty comment behind the key
+        let written = known_hosts.write_string(&host, KnownHostFileKind::OpenSSH)?;
+        line.push_str(written.trim_end());
+        line.push('\n');
+    }
+
+    // Don't glue the line onto a last entry that lacks its newline
+    let needs_newline = fs::read(file)
+        .map(|content| content.last().is_some_and(|byte| *byte != b'\n'))
+        .unwrap_or(false);
+    if needs_newline {
+        line.insert(0, '\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o644)
+        .open(file)?
+        .write_all(line.as_bytes())
+}
+
+/// Decide what the result of a known_hosts lookup means under the policy
+///
+/// # Arguments
+/// * `check` - Result of looking the host key up in known_hosts
+/// * `hostname` - Target server hostname, used in error messages
+/// * `known_hosts_file` - File the key was looked up in, used in error messages
+/// * `host_key_checking` - Policy for unknown and changed host keys
+///
+/// # Returns
+/// * `Result<bool, HostError>` - Whether the key must be added to known_hosts, or why it is refused
+fn judge_host_key(
+    check: CheckResult,
+    hostname: &str,
+    known_hosts_file: &Path,
+    host_key_checking: HostKeyChecking,
+) -> Result<bool, HostError> {
+    match check {
+        CheckResult::Match => Ok(false),
+        CheckResult::Mismatch => Err(HostError::HostKey(format!(
+            "key of {} differs from {}",
+            hostname,
+            known_hosts_file.display()
+        ))),
+        CheckResult::NotFound if host_key_checking == HostKeyChecking::Strict => {
+            Err(HostError::HostKey(format!(
+                "no host key is known for {} and strict checking is requested",
+                hostname
+            )))
+        }
+        CheckResult::NotFound => Ok(true),
+        CheckResult::Failure => Err(HostError::HostKey(format!(
+            "failed to check the host key of {}",
+            hostname
+        ))),
+    }
+}
+
+/// Open an authenticated SSH session to a host
+///
+/// # Arguments
+/// * `transport` - Transport settings used unless the host overrides them
+/// * `host` - Target host with its resolved IP address
+/// * `ip` - Resolved IP address to connect to
+///
+/// # Returns
+/// * `Result<Session, HostError>` - Ready session or the categorized failure
+fn connect(transport: &NativeTransport, host: &Host, ip: IpAddr) -> Result<Session, HostError> {
+    // Host keys are recorded under the real hostname, not an ssh_config alias
+    let hostname = host.address.as_ref().unwrap_or(&host.name).as_str();
+    let username = host.user.as_ref().unwrap_or(&transport.username);
+    let port = host.port.or(transport.port).unwrap_or(SSH_PORT);
+    let timeout = transport
+        .connect_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let tcp = TcpStream::connect_timeout(&SocketAddr::new(ip, port), timeout)
+        .map_err(|e| HostError::Connect(e.to_string()))?;
+
+    let mut session = Session::new().map_err(|e| HostError::Other(e.to_string()))?;
+    session.set_tcp_stream(tcp);
+    // The handshake and authentication block, so a stalled server must not hold them forever
+    session.set_timeout(timeout.as_millis() as u32);
+    session
+        .handshake()
+        .map_err(|e| HostError::Connect(format!("SSH handshake failed: {}", e)))?;
+
+    if transport.host_key_checking != HostKeyChecking::Off {
+        verify_host_key(&session, hostname, port, transport.host_key_checking)?;
+    }
+
+    // An explicit identity wins, then the SSH agent, then the default private keys
+    let identity = host.identity.as_ref().or(transport.identity.as_ref());
+    if let Some(identity) = identity {
+        if let Err(e) = session.userauth_pubkey_file(username, None, identity, None) {
+            return Err(HostError::Auth(format!("{}: {}", identity.display(), e)));
+        }
+    } else if session.userauth_agent(username).is_err() {
+        for identity in DEFAULT_IDENTITIES.iter().map(|name| ssh_dir().join(name)) {
+            if identity.exists()
+                && session
+                    .userauth_pubkey_file(username, None, &identity, None)
+                    .is_ok()
+            {
+                break;
+            }
+        }
+    }
+    if !session.authenticated() {
+        return Err(HostError::Auth(format!(
+            "no agent identity or key file accepted for {}",
+            username
+        )));
+    }
+
+    // The command itself is only limited by --timeout
+    session.set_timeout(0);
+    Ok(session)
+}
+
+/// Runs commands over in-process SSH sessions
+pub struct NativeTransport {
+    /// SSH username
+    pub username: String,
+    /// Optional limit on how long the command may run
+    pub timeout: Option<Duration>,
+    /// Optional SSH connection timeout in seconds
+    pub connect_timeout: Option<u64>,
+    /// Policy for unknown and changed host keys
+    pub host_key_checking: HostKeyChecking,
+    /// Default SSH port
+    pub port: Option<u16>,
+    /// Default identity file
+    pub identity: Option<PathBuf>,
+}
+
+impl Transport for NativeTransport {
+    fn execute(
+        &self,
+        host: &Host,
+        command: &str,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let ip = host
+            .ip
+            .ok_or_else(|| HostError::Other(format!("{} was not resolved", host.name)))?;
+        let session = connect(self, host, ip)?;
+        execute_native_command(session, command, input, self.timeout, on_line)
+    }
+
+    fn copy(
+        &self,
+        host: &Host,
+        transfer: &Transfer,
+        _on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let ip = host
+            .ip
+            .ok_or_else(|| HostError::Other(format!("{} was not resolved", host.name)))?;
+        let session = connect(self, host, ip)?;
+        if let Some(timeout) = self.timeout {
+            session.set_timeout(timeout.as_millis() as u32);
+        }
+        match transfer {
+            Transfer::Push { local, remote } => scp_push(&session, local, remote),
+            Transfer::Pull { remote, local } => scp_pull(&session, remote, local),
+        }
+    }
+}
+
+/// Upload a single local file over SCP
+///
+/// # Arguments
+/// * `session` - Authenticated session to the host
+/// * `local` - Local file to upload
+/// * `remote` - Remote file path, or a directory ending in `/`
+///
+/// # Returns
+/// * `Result<Outcome, HostError>` - Success outcome or the failure
+fn scp_push(session: &Session, local: &Path, remote: &str) -> Result<Outcome, HostError> {
+    let data = fs::read(local)
+        .map_err(|e| HostError::Other(format!("Failed to read {}: {}", local.display(), e)))?;
+    let mode = fs::metadata(local)
+        .map(|metadata| metadata.permissions().mode() & 0o777)
+        .unwrap_or(0o644);
+    let remote = match (remote.ends_with('/'), local.file_name()) {
+        (true, Some(name)) => Path::new(remote).join(name),
+        _ => PathBuf::from(remote),
+    };
+
+    let mut channel = session
+        .scp_send(&remote, mode as i32, data.len() as u64, None)
+        .map_err(|e| HostError::Other(format!("Failed to upload {}: {}", remote.display(), e)))?;
+    channel
+        .write_all(&data)
+        .and_then(|_| channel.send_eof().map_err(io::Error::from))
+        .and_then(|_| channel.wait_eof().map_err(io::Error::from))
+        .and_then(|_| channel.close().map_err(io::Error::from))
+        .and_then(|_| channel.wait_close().map_err(io::Error::from))
+        .map_err(|e| HostError::Other(format!("Failed to upload {}: {}", remote.display(), e)))?;
+    Ok(Outcome::Exited(0))
+}
+
+/// Download a single remote file over SCP into a local directory
+///
+/// Remote directories are refused by the server's scp, which is reported as the error.
+///
+/// # Arguments
+/// * `session` - Authenticated session to the host
+/// * `remote` - Remote file path
+/// * `local` - Local directory receiving the file
+///
+/// # Returns
+/// * `Result<Outcome, HostError>` - Success outcome or the failure
+fn scp_pull(session: &Session, remote: &str, local: &Path) -> Result<Outcome, HostError> {
+    let (mut channel, _) = session
+        .scp_recv(Path::new(remote))
+        .map_err(|e| HostError::Other(format!("Failed to download {}: {}", remote, e)))?;
+    let mut data = Vec::new();
+    channel
+        .read_to_end(&mut data)
+        .map_err(|e| HostError::Other(format!("Failed to download {}: {}", remote, e)))?;
+
+    let name = Path::new(remote)
+        .file_name()
+        .ok_or_else(|| HostError::Other(format!("{} does not name a file", remote)))?;
+    let target = local.join(name);
+    fs::write(&target, data)
+        .map_err(|e| HostError::Other(format!("Failed to write {}: {}", target.display(), e)))?;
+    Ok(Outcome::Exited(0))
+}
+
+/// Execute a command over an authenticated in-process SSH session
+///
+/// # Arguments
+/// * `session` - Authenticated session to the host
+/// * `command` - Command to execute
+/// * `input` - Optional data sent to the command's standard input
+/// * `timeout` - Optional limit on how long the command may run
+/// * `on_line` - Callback receiving each output line with its stream
+///
+/// # Returns
+/// * `Result<Outcome, HostError>` - Command outcome on success or the categorized failure
+fn execute_native_command(
+    session: Session,
+    command: &str,
+    input: Option<&[u8]>,
+    timeout: Option<Duration>,
+    on_line: &(dyn Fn(Stream, String) + Sync),
+) -> Result<Outcome, HostError> {
+    let mut channel = session
+        .channel_session()
+        .map_err(|e| HostError::Other(format!("Failed to open channel: {}", e)))?;
+    channel
+        .exec(command)
+        .map_err(|e| HostError::Other(format!("Failed to execute command: {}", e)))?;
+
+    // Poll the input and both output streams without blocking so none of them can stall the others
+    session.set_blocking(false);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    // Without input the command gets EOF right away, so `cat` or `read` don't wait forever
+    let mut pending_input = Some(input.unwrap_or_default());
+    let mut stdout = LineBuffer::default();
+    let mut stderr = LineBuffer::default();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let mut idle = true;
+        // Send as much input as the channel window takes, then signal its end
+        if let Some(rest) = pending_input {
+            let sent = if rest.is_empty() {
+                channel.send_eof().map(|_| 0).map_err(io::Error::from)
+            } else {
+                channel.write(rest)
+            };
+            match sent {
+                Ok(_) if rest.is_empty() => pending_input = None,
+                Ok(n) => {
+                    idle = idle && n == 0;
+                    pending_input = Some(&rest[n..]);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                // The command may exit without reading everything, which is not an error
+                Err(_) => pending_input = None,
+            }
+        }
+
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            let read = match stream {
+                Stream::Stdout => channel.read(&mut buffer),
+                Stream::Stderr => channel.stderr().read(&mut buffer),
+            };
+            match read {
+                Ok(0) => (),
+                Ok(n) => {
+                    idle = false;
+                    match stream {
+                        Stream::Stdout => stdout.push(&buffer[..n], stream, on_line),
+                        Stream::Stderr => stderr.push(&buffer[..n], stream, on_line),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(HostError::Other(format!("Failed to read output: {}", e))),
+            }
+        }
+
+        if idle && channel.eof() {
+            break;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            // Dropping the session closes the connection and the remote command with it
+            stdout.finish(Stream::Stdout, on_line);
+            stderr.finish(Stream::Stderr, on_line);
+            return Ok(Outcome::TimedOut);
+        }
+        if idle {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    stdout.finish(Stream::Stdout, on_line);
+    stderr.finish(Stream::Stderr, on_line);
+
+    session.set_blocking(true);
+    channel
+        .wait_close()
+        .map_err(|e| HostError::Other(format!("Failed to close channel: {}", e)))?;
+    let code = channel
+        .exit_status()
+        .map_err(|e| HostError::Other(format!("Failed to read exit status: {}", e)))?;
+    Ok(Outcome::Exited(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    /// Feed chunks of output through a line buffer and collect the lines it hands out
+    fn split(chunks: &[&[u8]]) -> Vec<String> {
+        let lines = Mutex::new(Vec::new());
+        let on_line = |_stream, line| lines.lock().unwrap().push(line);
+        let mut buffer = LineBuffer::default();
+        for chunk in chunks {
+            buffer.push(chunk, Stream::Stdout, &on_line);
+        }
+        buffer.finish(Stream::Stdout, &on_line);
+        lines.into_inner().unwrap()
+    }
+
+    fn transport(port: u16) -> NativeTransport {
+        NativeTransport {
+            username: "rexec".to_string(),
+            timeout: Some(Duration::from_secs(10)),
+            connect_timeout: Some(1),
+            host_key_checking: HostKeyChecking::Off,
+            port: Some(port),
+            identity: None,
+        }
+    }
+
+    fn localhost() -> Host {
+        Host {
+            name: "127.0.0.1".to_string(),
+            ip: Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lines_split_across_reads() {
+        assert_eq!(split(&[b"hel", b"lo\nwor", b"ld\n"]), ["hello", "world"]);
+    }
+
+    #[test]
+    fn several_lines_in_one_read() {
+        assert_eq!(split(&[b"a\nb\n\nc\n"]), ["a", "b", "", "c"]);
+    }
+
+    #[test]
+    fn unterminated_last_line_is_flushed() {
+        assert_eq!(split(&[b"a\nb"]), ["a", "b"]);
+        assert!(split(&[b""]).is_empty());
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        assert_eq!(split(&[b"\xffok\n"]), ["\u{fffd}ok"]);
+    }
+
+    #[test]
+    fn host_key_decisions() {
+        let file = Path::new("/home/rexec/.ssh/known_hosts");
+        let judge = |check, policy| judge_host_key(check, "web1", file, policy);
+
+        assert_eq!(
+            judge(CheckResult::Match, HostKeyChecking::Strict),
+            Ok(false)
+        );
+        assert_eq!(
+            judge(CheckResult::NotFound, HostKeyChecking::AcceptNew),
+            Ok(true)
+        );
+        assert!(matches!(
+            judge(CheckResult::NotFound, HostKeyChecking::Strict),
+            Err(HostError::HostKey(_))
+        ));
+        assert_eq!(
+            judge(CheckResult::Mismatch, HostKeyChecking::AcceptNew),
+            Err(HostError::HostKey(
+                "key of web1 differs from /home/rexec/.ssh/known_hosts".to_string()
+            ))
+        );
+        assert!(matches!(
+            judge(CheckResult::Failure, HostKeyChecking::AcceptNew),
+            Err(HostError::HostKey(_))
+        ));
+    }
+
+    #[test]
+    fn new_host_keys_are_appended() {
+        let file = std::env::temp_dir().join(format!("rexec-known-hosts-{}", std::process::id()));
+        let existing = "# my hosts\na,b ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA";
+        fs::write(&file, existing).unwrap();
+
+        let session = Session::new().unwrap();
+        append_known_host(&session, &file, "[web1]:2222", b"key", HostKeyType::Ed25519).unwrap();
+        append_known_host(&session, &file, "web2", b"key", HostKeyType::Ed25519).unwrap();
+        let content = fs::read_to_string(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+
+        assert_eq!(
+            content,
+            format!(
+                "{}\n[web1]:2222 ssh-ed25519 a2V5\nweb2 ssh-ed25519 a2V5\n",
+                existing
+            )
+        );
+    }
+
+    #[test]
+    fn refused_connection_is_a_connect_error() {
+        // Bind and release a port so nothing listens on it
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let result = transport(port).execute(&localhost(), "true", None, &|_, _| ());
+        assert!(matches!(result, Err(HostError::Connect(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn silent_server_times_out_in_the_handshake() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let started = Instant::now();
+        let result = transport(port).execute(&localhost(), "true", None, &|_, _| ());
+        match result {
+            Err(HostError::Connect(e)) => assert!(e.contains("handshake"), "{}", e),
+            other => panic!("expected a connect error, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// Needs an sshd on 127.0.0.1 that accepts the current user with the SSH agent
+    /// or a default key, listening on the port given in REXEC_TEST_SSHD_PORT
+    #[test]
+    #[ignore]
+    fn runs_commands_on_local_sshd() {
+        let port = std::env::var("REXEC_TEST_SSHD_PORT")
+            .expect("REXEC_TEST_SSHD_PORT is not set")
+            .parse()
+            .expect("REXEC_TEST_SSHD_PORT is not a port");
+        let transport = NativeTransport {
+            username: whoami::username(),
+            ..transport(port)
+        };
+        let run = |command: &str, input: Option<&[u8]>| {
+            let lines = Mutex::new(Vec::new());
+            let outcome = transport
+                .execute(&localhost(), command, input, &|stream, line| {
+                    lines.lock().unwrap().push((stream, line))
+                })
+                .unwrap();
+            (outcome, lines.into_inner().unwrap())
+        };
+
+        let (outcome, lines) = run("echo out; echo err >&2; exit 3", None);
+        assert_eq!(outcome, Outcome::Exited(3));
+        assert!(lines.contains(&(Stream::Stdout, "out".to_string())));
+        assert!(lines.contains(&(Stream::Stderr, "err".to_string())));
+
+        // More input than fits into the channel window while the command keeps writing
+        let input = "line\n".repeat(200_000);
+        let (outcome, lines) = run("cat", Some(input.as_bytes()));
+        assert_eq!(outcome, Outcome::Exited(0));
+        assert_eq!(lines.len(), 200_000);
+
+        // Commands reading stdin see its end when there is no input
+        let (outcome, lines) = run("cat; echo done", None);
+        assert_eq!(outcome, Outcome::Exited(0));
+        assert_eq!(lines, [(Stream::Stdout, "done".to_string())]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Host source reading aliases from OpenSSH client configuration files
+//!
+//! Every `Host` pattern without wildcards becomes a host named after the
+//! alias. ssh is given the alias itself so that all of its settings apply;
+//! `HostName`, `User`, `Port`, `IdentityFile` and `ProxyJump` of its blocks are
+//! kept for DNS resolution, `--list` and the native transport.
+
+use std::fs::{self, read_to_string};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::Host;
+
+/// Maximum depth of nested `Include` directives, same as OpenSSH
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Read all concrete host aliases from an ssh_config file and its includes
+///
+/// # Arguments
+/// * `path` - Path of the ssh_config file
+///
+/// # Returns
+/// * `io::Result<Vec<Host>>` - Hosts in order of appearance or the error reading the top-level file
+pub fn read_ssh_config(path: &Path) -> io::Result<Vec<Host>> {
+    let mut hosts: Vec<Host> = Vec::new();
+    parse_file(path, 0, &mut hosts, &mut Vec::new())?;
+    Ok(hosts)
+}
+
+/// Parse one file, appending new aliases and applying settings to the current block
+///
+/// # Arguments
+/// * `path` - File to parse
+/// * `depth` - Current `Include` nesting level
+/// * `hosts` - Aliases collected so far
+/// * `current` - Indices into `hosts` of the aliases the current block applies to
+fn parse_file(
+    path: &Path,
+    depth: usize,
+    hosts: &mut Vec<Host>,
+    current: &mut Vec<usize>,
+) -> io::Result<()> {
+    let content = read_to_string(path)?;
+
+    for line in content.lines() {
+        let (keyword, args) = match split_line(line) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+
+        match keyword.as_str() {
+            "host" => {
+                current.clear();
+                for pattern in args {
+                    // Wildcards and negations describe groups of hosts, not hosts
+                    if pattern.contains(['*', '?', '!']) {
+                        continue;
+                    }
+                    let idx = match hosts.iter().position(|h| h.name == pattern) {
+                        Some(idx) => idx,
+                        None => {
+                            hosts.push(Host {
+                                name: pattern,
+                                ssh_alias: true,
+                                ..Default::default()
+                            });
+                            hosts.len() - 1
+                        }
+                    };
+                    current.push(idx);
+                }
+            }
+            // Conditional blocks can't be evaluated here, so their settings are ignored
+            "match" => current.clear(),
+            "include" if depth < MAX_INCLUDE_DEPTH => {
+                for pattern in args {
+                    for included in expand_include(&pattern) {
+                        // Missing or unreadable includes are skipped, like ssh does
+                        let _ = parse_file(&included, depth + 1, hosts, current);
+                    }
+                }
+            }
+            _ => {
+                let value = match args.into_iter().next() {
+                    Some(value) => value,
+                    None => continue,
+                };
+                // The first obtained value of every setting wins
+                for &idx in current.iter() {
+                    let host = &mut hosts[idx];
+                    match keyword.as_str() {
+                        "hostname" if host.address.is_none() => {
+                            host.address = Some(expand_hostname(&value, &host.name))
+                        }
+                        "user" if host.user.is_none() => host.user = Some(value.clone()),
+                        "port" if host.port.is_none() => host.port = value.parse().ok(),
+                        "identityfile" if host.identity.is_none() => {
+                            host.identity = Some(expand_tilde(&value))
+                        }
+                        // `none` is kept as well, it turns the jump host off for this alias
+                        "proxyjump" if host.jump_host.is_none() => {
+                            host.jump_host = Some(value.clone())
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Split a configuration line into a lowercase keyword and its arguments
+///
+/// # Arguments
+/// * `line` - Raw line of the file
+///
+/// # Returns
+/// * `Option<(String, Vec<String>)>` - Keyword and unquoted arguments, None for blank lines and comments
+fn split_line(line: &str) -> Option<(String, Vec<String>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    // The keyword is separated by whitespace or a single '='
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let keyword = line[..end].to_lowercase();
+    let rest = line[end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest);
+
+    // Arguments are whitespace separated, double quotes group words
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut quoted = false;
+    for c in rest.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    Some((keyword, args))
+}
+
+/// Expand the `%h` and `%%` tokens ssh allows in a `HostName`
+///
+/// # Arguments
+/// * `value` - Argument of the `HostName` directive
+/// * `alias` - Alias the block applies to, which `%h` stands for
+///
+/// # Returns
+/// * `String` - Hostname to resolve
+fn expand_hostname(value: &str, alias: &str) -> String {
+    let mut hostname = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('h')) => {
+                hostname.push_str(alias);
+                chars.next();
+            }
+            ('%', Some('%')) => {
+                hostname.push('%');
+                chars.next();
+            }
+            (c, _) => hostname.push(c),
+        }
+    }
+    hostname
+}
+
+/// Replace a leading `~` with the user's home directory
+fn expand_tilde(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => crate::home_dir().join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Resolve an `Include` argument to the files it names
+///
+/// Relative paths are taken from `~/.ssh`, and wildcards in the file name
+/// are matched against the directory contents in sorted order.
+///
+/// # Arguments
+/// * `pattern` - Argument of the `Include` directive
+///
+/// # Returns
+/// * `Vec<PathBuf>` - Existing files matching the pattern
+fn expand_include(pattern: &str) -> Vec<PathBuf> {
+    let path = expand_tilde(pattern);
+    let path = if path.is_absolute() {
+        path
+    } else {
+        crate::home_dir().join(".ssh").join(path)
+    };
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !file_name.contains(['*', '?']) {
+        return vec![path];
+    }
+
+    // Translate the glob in the file name into an anchored regex
+    let glob = regex::escape(&file_name)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    let re = match Regex::new(&format!("^{}$", glob)) {
+        Ok(re) => re,
+        Err(_) => return Vec::new(),
+    };
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let mut matches: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter(|entry| re.is_match(&entry.file_name().to_string_lossy()))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    matches.sort();
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostname_tokens_are_expanded() {
+        assert_eq!(expand_hostname("%h.corp", "db-1"), "db-1.corp");
+        assert_eq!(expand_hostname("10.0.0.1", "db-1"), "10.0.0.1");
+        assert_eq!(expand_hostname("%%h-%h", "db-1"), "%h-db-1");
+    }
+}
@@ -0,0 +1,602 @@
+//! Transports deliver a command to a single target and stream its output back
+//!
+//! Host expansion, scheduling and output rendering in `main` only talk to the
+//! [`Transport`] trait, so the same fan-out drives ssh, a local shell,
+//! `docker exec` or `kubectl exec`.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::{Host, HostError, HostKeyChecking, Outcome, Stream, SSH_CONNECTION_ERROR};
+
+/// A file copy between this machine and a target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transfer {
+    /// Copy a local file or directory to a path on the target
+    Push { local: PathBuf, remote: String },
+    /// Copy a path on the target into a local directory
+    Pull { remote: String, local: PathBuf },
+}
+
+/// Process groups of the running children that were moved out of the terminal's group
+static CHILD_GROUPS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+/// Pass Ctrl-C and SIGTERM on to children running in their own process group
+///
+/// Children started with a timeout lead their own process group, so the
+/// terminal's SIGINT no longer reaches them. Both signals are blocked here and
+/// picked up by a dedicated thread, which forwards them to every live group and
+/// exits. Must be called before any other thread starts, so all threads inherit
+/// the signal mask; spawned processes get an empty mask again.
+pub fn forward_interrupts() {
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    };
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            return;
+        }
+        // Holding the lock keeps new children from being spawned behind our back
+        let groups = CHILD_GROUPS.lock().unwrap();
+        for group in groups.iter() {
+            unsafe {
+                libc::killpg(*group, signal);
+            }
+        }
+        warn!("Interrupted");
+        std::process::exit(128 + signal);
+    });
+}
+
+/// A way of running a command on a single target
+pub trait Transport: Sync {
+    /// Run `command` on `host` and hand every output line to `on_line`
+    ///
+    /// # Arguments
+    /// * `host` - Host, container or pod the command is run on, resolved if needed
+    /// * `command` - Command to execute
+    /// * `input` - Optional data fed to the command's standard input
+    /// * `on_line` - Callback receiving each output line with its stream
+    ///
+    /// # Returns
+    /// * `Result<Outcome, HostError>` - Command outcome on success or error
+    fn execute(
+        &self,
+        host: &Host,
+        command: &str,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError>;
+
+    /// Copy files between this machine and `host`
+    ///
+    /// # Arguments
+    /// * `host` - Host, container or pod to copy to or from
+    /// * `transfer` - Direction and paths of the copy; pulls name the local directory to copy into
+    /// * `on_line` - Callback receiving each line the copy tool prints
+    ///
+    /// # Returns
+    /// * `Result<Outcome, HostError>` - Outcome of the copy on success or error
+    fn copy(
+        &self,
+        host: &Host,
+        transfer: &Transfer,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError>;
+
+    /// Whether `host` is a network host that must be resolved via DNS first
+    fn resolves(&self, _host: &Host) -> bool {
+        true
+    }
+}
+
+/// Wait for a child process, killing its whole process group after the timeout
+///
+/// # Arguments
+/// * `child` - Spawned process, expected to lead its own process group
+/// * `timeout` - Optional deadline for the process to finish
+///
+/// # Returns
+/// * `io::Result<Option<ExitStatus>>` - Exit status, or None if the process was killed
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Some),
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            // Kill the process together with anything it spawned (e.g. ProxyCommand)
+            unsafe {
+                libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+            }
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Spawn a local process and stream its output line by line
+///
+/// # Arguments
+/// * `cmd` - Fully prepared command to spawn
+/// * `timeout` - Optional limit on how long the process may run
+/// * `input` - Optional data written to the process's standard input
+/// * `on_line` - Callback receiving each output line with its stream
+///
+/// # Returns
+/// * `Result<Outcome, HostError>` - Process outcome on success or error
+fn run_process(
+    mut cmd: Command,
+    timeout: Option<Duration>,
+    input: Option<&[u8]>,
+    on_line: &(dyn Fn(Stream, String) + Sync),
+) -> Result<Outcome, HostError> {
+    // Children only get the input they are given, so a stray ssh can never read the terminal
+    let stdin = match input {
+        Some(_) => Stdio::piped(),
+        None => Stdio::null(),
+    };
+    cmd.stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // Run the process in its own process group so the whole group can be killed on timeout
+    if timeout.is_some() {
+        cmd.process_group(0);
+    }
+
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let mut groups = CHILD_GROUPS.lock().unwrap();
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            return Err(HostError::Other(format!(
+                "Failed to start {} process: {}",
+                program, e
+            )))
+        }
+    };
+    // Register the group so an interrupt can be forwarded to it
+    if timeout.is_some() {
+        groups.push(child.id() as libc::pid_t);
+    }
+    drop(groups);
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdin = child.stdin.take();
+
+    let status = thread::scope(|scope| {
+        // Feed the input from its own thread so a full pipe can't block reading the output
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            scope.spawn(move || {
+                // The command may exit without reading everything, which is not an error
+                let _ = stdin.write_all(input);
+            });
+        }
+        // Capture stdout and stderr in real-time using dedicated threads
+        scope.spawn(|| {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                on_line(Stream::Stdout, line);
+            }
+        });
+        scope.spawn(|| {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                on_line(Stream::Stderr, line);
+            }
+        });
+
+        // Wait for command to complete or for the timeout to expire
+        wait_with_timeout(&mut child, timeout)
+    });
+    if timeout.is_some() {
+        let group = child.id() as libc::pid_t;
+        CHILD_GROUPS.lock().unwrap().retain(|g| *g != group);
+    }
+
+    match status {
+        Ok(Some(status)) => Ok(Outcome::Exited(status.code().unwrap_or(-1))),
+        Ok(None) => Ok(Outcome::TimedOut),
+        Err(e) => Err(HostError::Other(format!(
+            "Failed to wait for {} process: {}",
+            program, e
+        ))),
+    }
+}
+
+/// Classify an error message printed by the ssh client itself
+///
+/// # Arguments
+/// * `line` - Line of ssh's standard error
+///
+/// # Returns
+/// * `Option<HostError>` - Categorized failure if the line explains why ssh gave up
+fn classify_ssh_error(line: &str) -> Option<HostError> {
+    const HOST_KEY: [&str; 3] = [
+        "Host key verification failed",
+        "REMOTE HOST IDENTIFICATION HAS CHANGED",
+        "host key is known for",
+    ];
+    const AUTH: [&str; 2] = ["Permission denied", "Too many authentication failures"];
+    const CONNECT: [&str; 6] = [
+        "Connection refused",
+        "Connection timed out",
+        "No route to host",
+        "Could not resolve hostname",
+        "Connection closed by",
+        "Network is unreachable",
+    ];
+
+    let matches = |patterns: &[&str]| patterns.iter().any(|p| line.contains(p));
+    if matches(&HOST_KEY) {
+        Some(HostError::HostKey(line.to_string()))
+    } else if matches(&AUTH) {
+        Some(HostError::Auth(line.to_string()))
+    } else if matches(&CONNECT) {
+        Some(HostError::Connect(line.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Runs commands through the system SSH client
+pub struct SshTransport {
+    /// SSH username, ssh picks one from its config or the current user when unset
+    pub username: Option<String>,
+    /// Optional limit on how long the command may run
+    pub timeout: Option<Duration>,
+    /// Optional SSH connection timeout in seconds
+    pub connect_timeout: Option<u64>,
+    /// Policy for unknown and changed host keys
+    pub host_key_checking: HostKeyChecking,
+    /// Default SSH port
+    pub port: Option<u16>,
+    /// Default identity file
+    pub identity: Option<PathBuf>,
+    /// Default jump host
+    pub jump_host: Option<String>,
+    /// Extra `-o` options for every host
+    pub options: Vec<String>,
+    /// Alternative ssh_config file
+    pub config_file: Option<PathBuf>,
+}
+
+impl SshTransport {
+    /// Start an ssh or scp command line with the connection settings of a host
+    ///
+    /// # Arguments
+    /// * `program` - `ssh` or `scp`
+    /// * `port_flag` - Flag selecting the port, `-p` for ssh and `-P` for scp
+    /// * `host` - Target host whose overrides take precedence
+    ///
+    /// # Returns
+    /// * `Command` - Command ready for the destination arguments
+    fn command(&self, program: &str, port_flag: &str, host: &Host) -> Command {
+        let mut cmd = Command::new(program);
+        if let Some(config_file) = &self.config_file {
+            cmd.arg("-F").arg(config_file);
+        }
+
+        // ssh uses the first value it sees for an option, so per-host settings go first
+        for option in host.ssh_options.iter().chain(&self.options) {
+            cmd.arg("-o").arg(option);
+        }
+        if let Some(port) = host.port.or(self.port) {
+            cmd.arg(port_flag).arg(port.to_string());
+        }
+        if let Some(identity) = host.identity.as_ref().or(self.identity.as_ref()) {
+            cmd.arg("-i").arg(identity);
+        }
+        // A host's `ProxyJump none` from ssh_config turns off the default jump host too
+        match host.jump_host.as_ref().or(self.jump_host.as_ref()) {
+            Some(jump_host) if !jump_host.eq_ignore_ascii_case("none") => {
+                cmd.arg("-J").arg(jump_host);
+            }
+            _ => (),
+        }
+        cmd.arg("-o")
+            .arg(format!(
+                "StrictHostKeyChecking={}",
+                self.host_key_checking.ssh_option()
+            ))
+            .arg("-o")
+            .arg("BatchMode=yes");
+        if let Some(seconds) = self.connect_timeout {
+            cmd.arg("-o").arg(format!("ConnectTimeout={}", seconds));
+        }
+        cmd
+    }
+
+    /// The `[user@]address` destination of a host
+    ///
+    /// # Arguments
+    /// * `host` - Target host
+    /// * `bracket_ipv6` - Wrap IPv6 addresses in brackets, as scp needs in front of a path
+    ///
+    /// # Returns
+    /// * `String` - Destination argument for ssh or scp
+    fn destination(&self, host: &Host, bracket_ipv6: bool) -> String {
+        // ssh only applies an alias's own settings (ProxyCommand, ...) when given the alias
+        let address = match &host.address {
+            Some(address) if !host.ssh_alias => address,
+            _ => &host.name,
+        };
+        let address = if bracket_ipv6 && address.contains(':') {
+            format!("[{}]", address)
+        } else {
+            address.to_string()
+        };
+        // Without a user ssh falls back to its config (`User` of `Host *` too) or the current user
+        match host.user.as_ref().or(self.username.as_ref()) {
+            Some(username) => format!("{}@{}", username, address),
+            None => address,
+        }
+    }
+
+    /// Run an ssh or scp process and turn its own failures into errors
+    ///
+    /// # Arguments
+    /// * `cmd` - Prepared ssh or scp command
+    /// * `input` - Optional data fed to the process's standard input
+    /// * `on_line` - Callback receiving each output line with its stream
+    /// * `failed` - Whether the exit code means the client itself may have failed
+    ///
+    /// # Returns
+    /// * `Result<Outcome, HostError>` - Process outcome, or the failure ssh reported
+    fn run(
+        &self,
+        cmd: Command,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+        failed: impl Fn(i32) -> bool,
+    ) -> Result<Outcome, HostError> {
+        // Remember the first line in which ssh explains a connection failure
+        let ssh_error: Mutex<Option<HostError>> = Mutex::new(None);
+        let outcome = run_process(cmd, self.timeout, input, &|stream, line| {
+            if stream == Stream::Stderr {
+                let mut ssh_error = ssh_error.lock().unwrap();
+                if ssh_error.is_none() {
+                    *ssh_error = classify_ssh_error(&line);
+                }
+            }
+            on_line(stream, line)
+        })?;
+
+        match (outcome, ssh_error.into_inner().unwrap()) {
+            (Outcome::Exited(code), Some(e)) if failed(code) => Err(e),
+            (outcome, _) => Ok(outcome),
+        }
+    }
+}
+
+impl Transport for SshTransport {
+    fn execute(
+        &self,
+        host: &Host,
+        command: &str,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        // Build the SSH command with appropriate options
+        let mut ssh_cmd = self.command("ssh", "-p", host);
+        ssh_cmd.arg(self.destination(host, false)).arg(command);
+
+        // ssh exits with 255 when it fails itself rather than the remote command,
+        // so that code is a connection failure even when no message explained it
+        match self.run(ssh_cmd, input, on_line, |code| code == SSH_CONNECTION_ERROR)? {
+            Outcome::Exited(SSH_CONNECTION_ERROR) => Err(HostError::Connect(format!(
+                "ssh exited with status {}",
+                SSH_CONNECTION_ERROR
+            ))),
+            outcome => Ok(outcome),
+        }
+    }
+
+    fn copy(
+        &self,
+        host: &Host,
+        transfer: &Transfer,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let mut scp_cmd = self.command("scp", "-P", host);
+        // IPv6 addresses must be bracketed in front of the remote path
+        let destination = self.destination(host, true);
+        scp_cmd.arg("-r");
+        match transfer {
+            Transfer::Push { local, remote } => scp_cmd
+                .arg(local)
+                .arg(format!("{}:{}", destination, remote)),
+            Transfer::Pull { remote, local } => scp_cmd
+                .arg(format!("{}:{}", destination, remote))
+                .arg(local),
+        };
+
+        // scp reports its own failures with a non-zero exit code
+        self.run(scp_cmd, None, on_line, |code| code != 0)
+    }
+
+    fn resolves(&self, host: &Host) -> bool {
+        // Hosts behind a jump host often only resolve from there, ssh looks them up itself
+        let jump_host = host.jump_host.as_ref().or(self.jump_host.as_ref());
+        let proxied = host.ssh_options.iter().chain(&self.options).any(|option| {
+            let (key, value) = option
+                .split_once(['=', ' '])
+                .unwrap_or((option.as_str(), ""));
+            matches!(
+                key.to_ascii_lowercase().as_str(),
+                "proxyjump" | "proxycommand"
+            ) && !value.trim().eq_ignore_ascii_case("none")
+        });
+        !proxied && jump_host.is_none_or(|jump_host| jump_host.eq_ignore_ascii_case("none"))
+    }
+}
+
+/// Runs commands in a local shell, using the target only as a label
+pub struct LocalTransport {
+    /// Optional limit on how long the command may run
+    pub timeout: Option<Duration>,
+}
+
+impl Transport for LocalTransport {
+    fn execute(
+        &self,
+        _host: &Host,
+        command: &str,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let mut sh_cmd = Command::new("sh");
+        sh_cmd.arg("-c").arg(command);
+        run_process(sh_cmd, self.timeout, input, on_line)
+    }
+
+    fn copy(
+        &self,
+        _host: &Host,
+        transfer: &Transfer,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let mut cp_cmd = Command::new("cp");
+        cp_cmd.arg("-R");
+        match transfer {
+            Transfer::Push { local, remote } => cp_cmd.arg(local).arg(remote),
+            Transfer::Pull { remote, local } => cp_cmd.arg(remote).arg(local),
+        };
+        run_process(cp_cmd, self.timeout, None, on_line)
+    }
+
+    fn resolves(&self, _host: &Host) -> bool {
+        false
+    }
+}
+
+/// Runs commands inside running containers with `docker exec`
+pub struct DockerTransport {
+    /// Optional limit on how long the command may run
+    pub timeout: Option<Duration>,
+}
+
+impl Transport for DockerTransport {
+    fn execute(
+        &self,
+        host: &Host,
+        command: &str,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let mut docker_cmd = Command::new("docker");
+        docker_cmd.arg("exec");
+        // Without -i docker doesn't forward stdin into the container
+        if input.is_some() {
+            docker_cmd.arg("-i");
+        }
+        docker_cmd.arg(&host.name).arg("sh").arg("-c").arg(command);
+        run_process(docker_cmd, self.timeout, input, on_line)
+    }
+
+    fn copy(
+        &self,
+        host: &Host,
+        transfer: &Transfer,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let mut docker_cmd = Command::new("docker");
+        docker_cmd.arg("cp");
+        match transfer {
+            Transfer::Push { local, remote } => docker_cmd
+                .arg(local)
+                .arg(format!("{}:{}", host.name, remote)),
+            Transfer::Pull { remote, local } => docker_cmd
+                .arg(format!("{}:{}", host.name, remote))
+                .arg(local),
+        };
+        run_process(docker_cmd, self.timeout, None, on_line)
+    }
+
+    fn resolves(&self, _host: &Host) -> bool {
+        false
+    }
+}
+
+/// Runs commands inside pods with `kubectl exec`
+///
+/// Targets are pod names, optionally prefixed with their namespace as `namespace/pod`.
+pub struct KubectlTransport {
+    /// Optional limit on how long the command may run
+    pub timeout: Option<Duration>,
+}
+
+impl Transport for KubectlTransport {
+    fn execute(
+        &self,
+        host: &Host,
+        command: &str,
+        input: Option<&[u8]>,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        let mut kubectl_cmd = Command::new("kubectl");
+        kubectl_cmd.arg("exec");
+        if input.is_some() {
+            kubectl_cmd.arg("-i");
+        }
+        match host.name.split_once('/') {
+            Some((namespace, pod)) => kubectl_cmd.arg("-n").arg(namespace).arg(pod),
+            None => kubectl_cmd.arg(&host.name),
+        };
+        kubectl_cmd.arg("--").arg("sh").arg("-c").arg(command);
+        run_process(kubectl_cmd, self.timeout, input, on_line)
+    }
+
+    fn copy(
+        &self,
+        host: &Host,
+        transfer: &Transfer,
+        on_line: &(dyn Fn(Stream, String) + Sync),
+    ) -> Result<Outcome, HostError> {
+        // kubectl cp addresses pods as namespace/pod:path, just like our target names
+        let mut kubectl_cmd = Command::new("kubectl");
+        kubectl_cmd.arg("cp");
+        match transfer {
+            Transfer::Push { local, remote } => kubectl_cmd
+                .arg(local)
+                .arg(format!("{}:{}", host.name, remote)),
+            Transfer::Pull { remote, local } => {
+                // Unlike cp, kubectl cp doesn't copy into an existing directory
+                let name = Path::new(remote.trim_end_matches('/'))
+                    .file_name()
+                    .map(|name| local.join(name))
+                    .unwrap_or_else(|| local.clone());
+                kubectl_cmd
+                    .arg(format!("{}:{}", host.name, remote))
+                    .arg(name)
+            }
+        };
+        run_process(kubectl_cmd, self.timeout, None, on_line)
+    }
+
+    fn resolves(&self, _host: &Host) -> bool {
+        false
+    }
+}